@@ -0,0 +1,65 @@
+// How the backends reach the registers. The hardware implementations are the defaults, tests swap
+// in a recording mock so register sequences can be checked on the host.
+
+use crate::msr::Msr;
+
+/// 32 bit registers at byte offsets into the xAPIC page
+pub trait MmioRegisters {
+    fn read(&self, offset: usize) -> u32;
+    fn write(&mut self, offset: usize, value: u32);
+}
+
+/// The xAPIC page as mapped into the address space
+pub struct MmioPage {
+    base: *mut u8,
+}
+
+impl MmioPage {
+    /// # Safety
+    /// `base` must map the xAPIC page, uncached, for as long as the page is used
+    pub unsafe fn new(base: *mut u8) -> Self {
+        Self { base }
+    }
+}
+
+impl MmioRegisters for MmioPage {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { self.base.add(offset).cast::<u32>().read_volatile() }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        unsafe { self.base.add(offset).cast::<u32>().write_volatile(value) };
+    }
+}
+
+/// The x2APIC MSR range
+pub trait MsrRegisters {
+    fn read(&self, msr: u32) -> u64;
+    fn write(&mut self, msr: u32, value: u64);
+}
+
+/// `rdmsr`/`wrmsr` on the executing core
+pub struct CpuMsrs {
+    //Only the core it was created on may use it
+    _not_send: core::marker::PhantomData<*const ()>,
+}
+
+impl CpuMsrs {
+    /// # Safety
+    /// The local APIC must be in x2APIC mode whenever its registers are accessed
+    pub unsafe fn new() -> Self {
+        Self {
+            _not_send: core::marker::PhantomData,
+        }
+    }
+}
+
+impl MsrRegisters for CpuMsrs {
+    fn read(&self, msr: u32) -> u64 {
+        unsafe { Msr(msr).read() }
+    }
+
+    fn write(&mut self, msr: u32, value: u64) {
+        unsafe { Msr(msr).write(value) };
+    }
+}
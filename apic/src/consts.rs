@@ -1,9 +1,22 @@
-
 //These const names are by specification, however IA32 does not imply narrow 32-bit compatality
 //APIC is fairly similar between architectures
 
-const IA32_APIC_BASE_MSR: usize = 0x1B;
-const IA32_APIC_BASE_MSR_BSP: usize = 1 << 8;
-const IA32_APIC_BASE_MSR_ENABLE: usize = 1 << 11;
-const IA32_APIC_BASE_MSR_X2APIC_ENABLE: usize = 1 << 10;
-const IA32_APIC_BASE_MSR_BASE_ADDR: usize = 1 << 12;
\ No newline at end of file
+pub(crate) const IA32_APIC_BASE_MSR: u32 = 0x1B;
+pub(crate) const IA32_APIC_BASE_MSR_BSP: u64 = 1 << 8;
+pub(crate) const IA32_APIC_BASE_MSR_X2APIC_ENABLE: u64 = 1 << 10;
+pub(crate) const IA32_APIC_BASE_MSR_ENABLE: u64 = 1 << 11;
+//Bits 12 up to MAXPHYADDR, the rest is reserved and reads as 0
+pub(crate) const IA32_APIC_BASE_MSR_BASE_ADDR: u64 = 0x000F_FFFF_FFFF_F000;
+
+pub(crate) const IA32_TSC_DEADLINE_MSR: u32 = 0x6E0;
+pub(crate) const IA32_EFER_MSR: u32 = 0xC000_0080;
+pub(crate) const IA32_FS_BASE_MSR: u32 = 0xC000_0100;
+pub(crate) const IA32_GS_BASE_MSR: u32 = 0xC000_0101;
+pub(crate) const IA32_KERNEL_GS_BASE_MSR: u32 = 0xC000_0102;
+
+//Spurious interrupt vector register: software enable
+pub(crate) const SVR_APIC_ENABLE: u32 = 1 << 8;
+//ICR/LVT delivery status, never set in x2APIC mode
+pub(crate) const DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+//x2APIC registers are MSRs starting here, one per 16 byte xAPIC register
+pub(crate) const X2APIC_MSR_BASE: u32 = 0x800;
@@ -0,0 +1,42 @@
+use crate::{Apic, ApicMode, Register, X2Apic, XApic};
+
+/// Either backend, whichever `get_apic_available` found at boot
+pub enum LocalApic {
+    XApic(XApic),
+    X2Apic(X2Apic),
+}
+
+macro_rules! dispatch {
+    ($self:ident, $apic:ident => $call:expr) => {
+        match $self {
+            LocalApic::XApic($apic) => $call,
+            LocalApic::X2Apic($apic) => $call,
+        }
+    };
+}
+
+impl Apic for LocalApic {
+    fn mode(&self) -> ApicMode {
+        dispatch!(self, apic => apic.mode())
+    }
+
+    fn read_register(&self, register: Register) -> u32 {
+        dispatch!(self, apic => apic.read_register(register))
+    }
+
+    fn write_register(&mut self, register: Register, value: u32) {
+        dispatch!(self, apic => apic.write_register(register, value))
+    }
+
+    fn write_icr(&mut self, icr: u64) {
+        dispatch!(self, apic => apic.write_icr(icr))
+    }
+
+    fn id(&self) -> u32 {
+        dispatch!(self, apic => apic.id())
+    }
+
+    fn ipi_pending(&self) -> bool {
+        dispatch!(self, apic => apic.ipi_pending())
+    }
+}
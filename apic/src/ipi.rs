@@ -0,0 +1,161 @@
+use bitflags::bitflags;
+
+use crate::{lvt::DeliveryMode, ApicMode};
+
+const ICR_DELIVERY_MODE_SHIFT: u64 = 8;
+const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+const ICR_LEVEL_TRIGGERED: u64 = 1 << 15;
+const ICR_SHORTHAND_SHIFT: u64 = 18;
+const XAPIC_DESTINATION_SHIFT: u64 = 56;
+const X2APIC_DESTINATION_SHIFT: u64 = 32;
+
+/// Who an IPI goes to. Only physical destination mode, by APIC ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// 8 bit in xAPIC mode, 32 bit in x2APIC mode
+    Physical(u32),
+    SelfOnly,
+    AllIncludingSelf,
+    AllExcludingSelf,
+}
+
+impl Destination {
+    const fn shorthand(self) -> u64 {
+        match self {
+            Destination::Physical(_) => 0b00,
+            Destination::SelfOnly => 0b01,
+            Destination::AllIncludingSelf => 0b10,
+            Destination::AllExcludingSelf => 0b11,
+        }
+    }
+}
+
+/// An inter-processor interrupt, written to the ICR by `Apic::send_ipi`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipi {
+    pub vector: u8,
+    pub delivery_mode: DeliveryMode,
+    pub destination: Destination,
+    /// Clear only for the INIT level de-assert
+    pub assert: bool,
+    pub level_triggered: bool,
+}
+
+impl Ipi {
+    pub const fn fixed(destination: Destination, vector: u8) -> Self {
+        Self {
+            vector,
+            delivery_mode: DeliveryMode::Fixed,
+            destination,
+            assert: true,
+            level_triggered: false,
+        }
+    }
+
+    pub const fn nmi(destination: Destination) -> Self {
+        Self {
+            delivery_mode: DeliveryMode::Nmi,
+            ..Self::fixed(destination, 0)
+        }
+    }
+
+    /// Puts the target into wait-for-SIPI
+    pub const fn init(destination: Destination) -> Self {
+        Self {
+            delivery_mode: DeliveryMode::Init,
+            level_triggered: true,
+            ..Self::fixed(destination, 0)
+        }
+    }
+
+    /// INIT level de-assert, only needed by the MP spec sequence for old CPUs
+    pub const fn init_deassert(destination: Destination) -> Self {
+        Self {
+            assert: false,
+            ..Self::init(destination)
+        }
+    }
+
+    /// Starts the target in real mode at `vector << 12`
+    pub const fn startup(destination: Destination, vector: u8) -> Self {
+        Self {
+            delivery_mode: DeliveryMode::StartUp,
+            ..Self::fixed(destination, vector)
+        }
+    }
+
+    /// The full 64 bit ICR value, the destination field moves with the mode
+    pub const fn icr(&self, mode: ApicMode) -> u64 {
+        let mut icr = self.vector as u64
+            | (self.delivery_mode as u64) << ICR_DELIVERY_MODE_SHIFT
+            | self.destination.shorthand() << ICR_SHORTHAND_SHIFT;
+        if self.assert {
+            icr |= ICR_LEVEL_ASSERT;
+        }
+        if self.level_triggered {
+            icr |= ICR_LEVEL_TRIGGERED;
+        }
+        if let Destination::Physical(apic_id) = self.destination {
+            icr |= match mode {
+                ApicMode::XApic => ((apic_id & 0xFF) as u64) << XAPIC_DESTINATION_SHIFT,
+                ApicMode::X2Apic => (apic_id as u64) << X2APIC_DESTINATION_SHIFT,
+            };
+        }
+        icr
+    }
+}
+
+bitflags! {
+    /// The error status register, as latched by the last write to it
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ErrorStatus: u32 {
+        const SEND_CHECKSUM = 1 << 0;
+        const RECEIVE_CHECKSUM = 1 << 1;
+        const SEND_ACCEPT = 1 << 2;
+        const RECEIVE_ACCEPT = 1 << 3;
+        const REDIRECTABLE_IPI = 1 << 4;
+        const SEND_ILLEGAL_VECTOR = 1 << 5;
+        const RECEIVE_ILLEGAL_VECTOR = 1 << 6;
+        const ILLEGAL_REGISTER_ADDRESS = 1 << 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_asserts_level_triggered() {
+        let icr = Ipi::init(Destination::Physical(1)).icr(ApicMode::XApic);
+        assert_eq!(icr, 0x0100_0000_0000_C500);
+        let icr = Ipi::init_deassert(Destination::Physical(1)).icr(ApicMode::XApic);
+        assert_eq!(icr, 0x0100_0000_0000_8500);
+    }
+
+    #[test]
+    fn startup_carries_the_page_number() {
+        let icr = Ipi::startup(Destination::Physical(2), 0x9F).icr(ApicMode::XApic);
+        assert_eq!(icr, 0x0200_0000_0000_469F);
+    }
+
+    #[test]
+    fn destination_field_depends_on_mode() {
+        let ipi = Ipi::fixed(Destination::Physical(0x1FF), 0x30);
+        //xAPIC IDs are 8 bit
+        assert_eq!(ipi.icr(ApicMode::XApic), 0xFF00_0000_0000_4030);
+        assert_eq!(ipi.icr(ApicMode::X2Apic), 0x0000_01FF_0000_4030);
+    }
+
+    #[test]
+    fn shorthands_leave_the_destination_empty() {
+        let cases = [
+            (Destination::SelfOnly, 0b01),
+            (Destination::AllIncludingSelf, 0b10),
+            (Destination::AllExcludingSelf, 0b11),
+        ];
+        for (destination, shorthand) in cases {
+            let icr = Ipi::nmi(destination).icr(ApicMode::X2Apic);
+            assert_eq!(icr, (shorthand << 18) | 0x4400);
+        }
+    }
+}
@@ -1,20 +1,163 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 // Can't be making a kernel without a 'fully' 'compliant' APIC module
 
 use raw_cpuid::CpuId;
 
+pub mod access;
+mod consts;
+#[cfg(feature = "dynamic_apic")]
+mod dynamic;
+mod ipi;
+mod lvt;
+#[cfg(test)]
+mod mock;
+pub mod msr;
+mod register;
+// Host tests cover both backends whatever the features say
+#[cfg(any(test, feature = "2xapic", feature = "dynamic_apic"))]
+mod x2apic;
+#[cfg(any(test, not(feature = "2xapic")))]
+mod xapic;
+
+#[cfg(all(feature = "2xapic", feature = "dynamic_apic"))]
+compile_error!("`2xapic` and `dynamic_apic` are mutually exclusive");
+
+pub use ipi::{Destination, ErrorStatus, Ipi};
+pub use lvt::{ApicVersion, DeliveryMode, Lvt, LvtEntry, TimerDivide, TimerMode};
+pub use register::Register;
+#[cfg(any(test, feature = "2xapic", feature = "dynamic_apic"))]
+pub use x2apic::X2Apic;
+#[cfg(any(test, not(feature = "2xapic")))]
+pub use xapic::XApic;
+
+// The backend the build targets. `2xapic` and the default each compile in a single backend, so
+// every call goes straight to it, `dynamic_apic` picks one at boot and matches on every access.
+#[cfg(feature = "2xapic")]
+pub type LocalApic = X2Apic;
+#[cfg(feature = "dynamic_apic")]
+pub use dynamic::LocalApic;
+#[cfg(not(any(feature = "2xapic", feature = "dynamic_apic")))]
+pub type LocalApic = XApic;
+
+/// Brings up the executing core's local APIC as the `LocalApic` of this build. `xapic_base` is
+/// where the xAPIC page is mapped, x2APIC mode doesn't use it.
+///
+/// # Safety
+/// The CPU must support the selected mode, and `xapic_base` must be valid whenever xAPIC mode
+/// can be selected, see `XApic::new`
+pub unsafe fn local_apic(xapic_base: *mut u8) -> LocalApic {
+    #[cfg(feature = "2xapic")]
+    let apic = {
+        let _ = xapic_base;
+        unsafe { X2Apic::new() }
+    };
+    #[cfg(feature = "dynamic_apic")]
+    let apic = match get_apic_available() {
+        Some(ApicMode::X2Apic) => LocalApic::X2Apic(unsafe { X2Apic::new() }),
+        Some(ApicMode::XApic) => LocalApic::XApic(unsafe { XApic::new(xapic_base) }),
+        None => panic!("no local APIC"),
+    };
+    #[cfg(not(any(feature = "2xapic", feature = "dynamic_apic")))]
+    let apic = unsafe { XApic::new(xapic_base) };
+    apic
+}
+
+use consts::{DELIVERY_STATUS_PENDING, SVR_APIC_ENABLE};
+
 //XAPIC is APIC compatible, no need to differentiate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum ApicMode {
     XApic,
     X2Apic,
 }
 
+/// The local APIC of the executing core. Backends only provide register access, everything else
+/// is shared. A handle always talks to the APIC of the core it runs on, so it shouldn't move
+/// between cores.
 pub trait Apic {
-    const APIC_MODE: ApicMode;
-}
+    fn mode(&self) -> ApicMode;
+    fn read_register(&self, register: Register) -> u32;
+    fn write_register(&mut self, register: Register, value: u32);
+    /// One 64 bit MSR write in x2APIC mode, two MMIO writes (high half first) in xAPIC mode
+    fn write_icr(&mut self, icr: u64);
+    /// 8 bit in xAPIC mode, 32 bit in x2APIC mode
+    fn id(&self) -> u32;
+
+    fn version(&self) -> ApicVersion {
+        ApicVersion::from_bits(self.read_register(Register::Version))
+    }
+
+    /// Software enables the APIC, interrupts it can't deliver end up at `spurious_vector`
+    fn enable(&mut self, spurious_vector: u8) {
+        self.write_register(Register::SpuriousVector, spurious_vector as u32 | SVR_APIC_ENABLE);
+    }
+
+    /// Software disables the APIC, which masks every LVT entry until it is enabled again
+    fn disable(&mut self) {
+        let svr = self.read_register(Register::SpuriousVector);
+        self.write_register(Register::SpuriousVector, svr & !SVR_APIC_ENABLE);
+    }
+
+    fn eoi(&mut self) {
+        self.write_register(Register::Eoi, 0);
+    }
+
+    fn task_priority(&self) -> u8 {
+        self.read_register(Register::TaskPriority) as u8
+    }
+
+    /// Interrupts with a priority class (vector >> 4) at or below `priority >> 4` are held back
+    fn set_task_priority(&mut self, priority: u8) {
+        self.write_register(Register::TaskPriority, priority as u32);
+    }
 
+    fn lvt(&self, entry: LvtEntry) -> Lvt {
+        Lvt::from_bits(self.read_register(entry.register()))
+    }
+
+    fn set_lvt(&mut self, entry: LvtEntry, lvt: Lvt) {
+        self.write_register(entry.register(), lvt.bits());
+    }
+
+    fn timer_divide(&self) -> TimerDivide {
+        TimerDivide::from_bits(self.read_register(Register::TimerDivide))
+    }
+
+    fn set_timer_divide(&mut self, divide: TimerDivide) {
+        self.write_register(Register::TimerDivide, divide.bits());
+    }
+
+    /// Starts the timer in one-shot and periodic mode, 0 stops it
+    fn set_timer_initial_count(&mut self, count: u32) {
+        self.write_register(Register::TimerInitialCount, count);
+    }
+
+    fn timer_initial_count(&self) -> u32 {
+        self.read_register(Register::TimerInitialCount)
+    }
+
+    fn timer_current_count(&self) -> u32 {
+        self.read_register(Register::TimerCurrentCount)
+    }
+
+    fn send_ipi(&mut self, ipi: Ipi) {
+        self.write_icr(ipi.icr(self.mode()));
+    }
+
+    /// The last IPI hasn't been accepted yet. Always false in x2APIC mode, which has no
+    /// delivery status.
+    fn ipi_pending(&self) -> bool {
+        self.read_register(Register::IcrLow) & DELIVERY_STATUS_PENDING != 0
+    }
+
+    /// Errors since the last call, the register only updates when written
+    fn error_status(&mut self) -> ErrorStatus {
+        self.write_register(Register::ErrorStatus, 0);
+        ErrorStatus::from_bits_retain(self.read_register(Register::ErrorStatus))
+    }
+}
 
 
 pub fn get_apic_available() -> Option<ApicMode> {
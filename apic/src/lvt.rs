@@ -0,0 +1,270 @@
+use crate::{consts::DELIVERY_STATUS_PENDING, register::Register};
+
+const LVT_VECTOR: u32 = 0xFF;
+const LVT_DELIVERY_MODE_SHIFT: u32 = 8;
+const LVT_DELIVERY_MODE: u32 = 0b111 << LVT_DELIVERY_MODE_SHIFT;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_SHIFT: u32 = 17;
+const LVT_TIMER_MODE: u32 = 0b11 << LVT_TIMER_MODE_SHIFT;
+
+/// The local vector table, one register per local interrupt source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LvtEntry {
+    Timer,
+    /// Corrected machine check, only there if `ApicVersion::max_lvt_entry` goes up to 6
+    Cmci,
+    Thermal,
+    PerformanceCounter,
+    Lint0,
+    Lint1,
+    Error,
+}
+
+impl LvtEntry {
+    pub const fn register(self) -> Register {
+        match self {
+            LvtEntry::Timer => Register::LvtTimer,
+            LvtEntry::Cmci => Register::LvtCmci,
+            LvtEntry::Thermal => Register::LvtThermal,
+            LvtEntry::PerformanceCounter => Register::LvtPerformanceCounter,
+            LvtEntry::Lint0 => Register::LvtLint0,
+            LvtEntry::Lint1 => Register::LvtLint1,
+            LvtEntry::Error => Register::LvtError,
+        }
+    }
+}
+
+/// Shared by the LVT and the ICR. The timer and error entries are always fixed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    /// ICR only
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    /// ICR only
+    StartUp = 0b110,
+    /// LVT only, for LINT0 wired to an 8259
+    ExtInt = 0b111,
+}
+
+impl DeliveryMode {
+    const fn from_bits(bits: u32) -> Self {
+        match bits & 0b111 {
+            0b001 => DeliveryMode::LowestPriority,
+            0b010 => DeliveryMode::Smi,
+            0b100 => DeliveryMode::Nmi,
+            0b101 => DeliveryMode::Init,
+            0b110 => DeliveryMode::StartUp,
+            0b111 => DeliveryMode::ExtInt,
+            //0b011 is reserved
+            _ => DeliveryMode::Fixed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerMode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+    /// Fires at `msr::TscDeadline`, the initial count register is ignored
+    TscDeadline = 0b10,
+}
+
+/// An LVT register value. Starts out as a fixed, edge triggered, active high and unmasked entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lvt(u32);
+
+impl Lvt {
+    pub const fn new(vector: u8) -> Self {
+        Self(vector as u32)
+    }
+
+    /// What the local APIC resets every entry to
+    pub const fn masked() -> Self {
+        Self(LVT_MASKED)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn vector(self) -> u8 {
+        (self.0 & LVT_VECTOR) as u8
+    }
+
+    pub const fn with_mask(self, masked: bool) -> Self {
+        self.with_bit(LVT_MASKED, masked)
+    }
+
+    pub const fn is_masked(self) -> bool {
+        self.0 & LVT_MASKED != 0
+    }
+
+    pub const fn with_delivery_mode(self, mode: DeliveryMode) -> Self {
+        Self((self.0 & !LVT_DELIVERY_MODE) | ((mode as u32) << LVT_DELIVERY_MODE_SHIFT))
+    }
+
+    pub const fn delivery_mode(self) -> DeliveryMode {
+        DeliveryMode::from_bits(self.0 >> LVT_DELIVERY_MODE_SHIFT)
+    }
+
+    /// LINT0 and LINT1 only
+    pub const fn with_level_triggered(self, level_triggered: bool) -> Self {
+        self.with_bit(LVT_LEVEL_TRIGGERED, level_triggered)
+    }
+
+    /// LINT0 and LINT1 only
+    pub const fn with_active_low(self, active_low: bool) -> Self {
+        self.with_bit(LVT_ACTIVE_LOW, active_low)
+    }
+
+    /// Timer entry only
+    pub const fn with_timer_mode(self, mode: TimerMode) -> Self {
+        Self((self.0 & !LVT_TIMER_MODE) | ((mode as u32) << LVT_TIMER_MODE_SHIFT))
+    }
+
+    pub const fn timer_mode(self) -> TimerMode {
+        match (self.0 & LVT_TIMER_MODE) >> LVT_TIMER_MODE_SHIFT {
+            0b01 => TimerMode::Periodic,
+            0b10 => TimerMode::TscDeadline,
+            //0b11 is reserved
+            _ => TimerMode::OneShot,
+        }
+    }
+
+    /// The interrupt was sent to the core but not accepted yet, read only
+    pub const fn is_pending(self) -> bool {
+        self.0 & DELIVERY_STATUS_PENDING != 0
+    }
+
+    const fn with_bit(self, bit: u32, set: bool) -> Self {
+        if set { Self(self.0 | bit) } else { Self(self.0 & !bit) }
+    }
+}
+
+/// Divide configuration register: the timer counts down once every N bus clocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerDivide {
+    //Bits 0, 1 and 3, bit 2 is reserved
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+impl TimerDivide {
+    pub const fn from_bits(bits: u32) -> Self {
+        match bits & 0b1011 {
+            0b0000 => TimerDivide::By2,
+            0b0001 => TimerDivide::By4,
+            0b0010 => TimerDivide::By8,
+            0b0011 => TimerDivide::By16,
+            0b1000 => TimerDivide::By32,
+            0b1001 => TimerDivide::By64,
+            0b1010 => TimerDivide::By128,
+            _ => TimerDivide::By1,
+        }
+    }
+
+    pub const fn bits(self) -> u32 {
+        self as u32
+    }
+
+    pub const fn divisor(self) -> u32 {
+        match self {
+            TimerDivide::By1 => 1,
+            TimerDivide::By2 => 2,
+            TimerDivide::By4 => 4,
+            TimerDivide::By8 => 8,
+            TimerDivide::By16 => 16,
+            TimerDivide::By32 => 32,
+            TimerDivide::By64 => 64,
+            TimerDivide::By128 => 128,
+        }
+    }
+}
+
+/// The version register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicVersion {
+    /// 0x1X for an integrated APIC, anything below is an external 82489DX
+    pub version: u8,
+    /// Index of the last LVT entry, one less than the number of entries
+    pub max_lvt_entry: u8,
+    pub eoi_broadcast_suppression: bool,
+}
+
+impl ApicVersion {
+    pub const fn from_bits(bits: u32) -> Self {
+        Self {
+            version: bits as u8,
+            max_lvt_entry: (bits >> 16) as u8,
+            eoi_broadcast_suppression: bits & (1 << 24) != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lvt_builder_sets_the_right_bits() {
+        assert_eq!(Lvt::masked().bits(), 0x1_0000);
+        let timer = Lvt::new(0x20).with_timer_mode(TimerMode::TscDeadline).with_mask(true);
+        assert_eq!(timer.bits(), 0x5_0020);
+        assert_eq!(timer.timer_mode(), TimerMode::TscDeadline);
+        assert!(timer.is_masked());
+        assert_eq!(timer.with_mask(false).bits(), 0x4_0020);
+
+        let lint0 = Lvt::new(0)
+            .with_delivery_mode(DeliveryMode::ExtInt)
+            .with_level_triggered(true)
+            .with_active_low(true);
+        assert_eq!(lint0.bits(), 0xA700);
+        assert_eq!(lint0.delivery_mode(), DeliveryMode::ExtInt);
+    }
+
+    #[test]
+    fn timer_divide_round_trips() {
+        let all = [
+            TimerDivide::By1,
+            TimerDivide::By2,
+            TimerDivide::By4,
+            TimerDivide::By8,
+            TimerDivide::By16,
+            TimerDivide::By32,
+            TimerDivide::By64,
+            TimerDivide::By128,
+        ];
+        for (i, divide) in all.into_iter().enumerate() {
+            assert_eq!(TimerDivide::from_bits(divide.bits()), divide);
+            assert_eq!(divide.divisor(), 1 << i);
+            //Bit 2 is reserved
+            assert_eq!(divide.bits() & 0b100, 0);
+        }
+    }
+
+    #[test]
+    fn version_fields() {
+        let version = ApicVersion::from_bits(0x0105_0014);
+        assert_eq!(version.version, 0x14);
+        assert_eq!(version.max_lvt_entry, 5);
+        assert!(version.eoi_broadcast_suppression);
+    }
+}
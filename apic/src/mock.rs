@@ -0,0 +1,74 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc, vec::Vec};
+
+use crate::access::{MmioRegisters, MsrRegisters};
+
+/// One register access, by MMIO offset or MSR number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    Read(u32, u64),
+    Write(u32, u64),
+}
+
+#[derive(Default)]
+struct State {
+    values: HashMap<u32, u64>,
+    read_only: HashMap<u32, u64>,
+    log: Vec<Access>,
+}
+
+/// In-memory register file that records every access. Clones share the same registers, so a test
+/// keeps one to inspect what the backend it handed the other to did.
+#[derive(Clone, Default)]
+pub(crate) struct MockRegisters(Rc<RefCell<State>>);
+
+impl MockRegisters {
+    /// Reads return what was last written, 0 before that
+    pub(crate) fn set(&self, address: u32, value: u64) {
+        self.0.borrow_mut().values.insert(address, value);
+    }
+
+    /// Reads always return `value`, writes are only logged
+    pub(crate) fn set_read_only(&self, address: u32, value: u64) {
+        self.0.borrow_mut().read_only.insert(address, value);
+    }
+
+    pub(crate) fn take_log(&self) -> Vec<Access> {
+        core::mem::take(&mut self.0.borrow_mut().log)
+    }
+
+    fn read_value(&self, address: u32) -> u64 {
+        let mut state = self.0.borrow_mut();
+        let value = match state.read_only.get(&address) {
+            Some(value) => *value,
+            None => state.values.get(&address).copied().unwrap_or(0),
+        };
+        state.log.push(Access::Read(address, value));
+        value
+    }
+
+    fn write_value(&self, address: u32, value: u64) {
+        let mut state = self.0.borrow_mut();
+        state.values.insert(address, value);
+        state.log.push(Access::Write(address, value));
+    }
+}
+
+impl MmioRegisters for MockRegisters {
+    fn read(&self, offset: usize) -> u32 {
+        self.read_value(offset as u32) as u32
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        self.write_value(offset as u32, value as u64);
+    }
+}
+
+impl MsrRegisters for MockRegisters {
+    fn read(&self, msr: u32) -> u64 {
+        self.read_value(msr)
+    }
+
+    fn write(&mut self, msr: u32, value: u64) {
+        self.write_value(msr, value);
+    }
+}
@@ -0,0 +1,182 @@
+// Model specific registers the APIC code and the kernel around it need, typed where the bits mean
+// something. Reading or writing an MSR the CPU doesn't implement is a #GP, so everything that
+// isn't architectural on every x86_64 CPU is unsafe to touch.
+
+use core::arch::asm;
+
+use bitflags::bitflags;
+
+use crate::consts::*;
+
+/// Any MSR by number, for the ones without a typed wrapper
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msr(pub u32);
+
+impl Msr {
+    /// # Safety
+    /// The MSR must exist on this CPU
+    #[inline]
+    pub unsafe fn read(self) -> u64 {
+        let (low, high): (u32, u32);
+        unsafe { asm!("rdmsr", in("ecx") self.0, out("eax") low, out("edx") high, options(nostack, preserves_flags)) };
+        ((high as u64) << 32) | low as u64
+    }
+
+    /// # Safety
+    /// The MSR must exist on this CPU and accept `value`, and changing it must not break any
+    /// assumption of the running code
+    #[inline]
+    pub unsafe fn write(self, value: u64) {
+        let (low, high) = (value as u32, (value >> 32) as u32);
+        unsafe { asm!("wrmsr", in("ecx") self.0, in("eax") low, in("edx") high, options(nostack, preserves_flags)) };
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ApicBaseFlags: u64 {
+        /// Read only, set on the bootstrap processor
+        const BSP = IA32_APIC_BASE_MSR_BSP;
+        const X2APIC_ENABLE = IA32_APIC_BASE_MSR_X2APIC_ENABLE;
+        const GLOBAL_ENABLE = IA32_APIC_BASE_MSR_ENABLE;
+    }
+}
+
+/// `IA32_APIC_BASE`: where the xAPIC registers are mapped and which mode the local APIC is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicBase {
+    pub flags: ApicBaseFlags,
+    /// Physical address of the xAPIC MMIO page, 4 KiB aligned
+    pub address: u64,
+}
+
+impl ApicBase {
+    pub const MSR: Msr = Msr(IA32_APIC_BASE_MSR);
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self {
+            flags: ApicBaseFlags::from_bits_truncate(bits),
+            address: bits & IA32_APIC_BASE_MSR_BASE_ADDR,
+        }
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.flags.bits() | (self.address & IA32_APIC_BASE_MSR_BASE_ADDR)
+    }
+
+    pub fn read() -> Self {
+        // Architectural on every CPU with a local APIC, which is every x86_64 CPU
+        Self::from_bits(unsafe { Self::MSR.read() })
+    }
+
+    /// Reserved bits are written as 0. Going from x2APIC back to xAPIC has to pass through
+    /// disabled, the CPU faults on the direct transition.
+    ///
+    /// # Safety
+    /// Moves or disables the local APIC underneath anything using it
+    pub unsafe fn write(self) {
+        unsafe { Self::MSR.write(self.bits()) };
+    }
+
+    pub fn is_bsp(&self) -> bool {
+        self.flags.contains(ApicBaseFlags::BSP)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.flags.contains(ApicBaseFlags::GLOBAL_ENABLE)
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        self.flags.contains(ApicBaseFlags::GLOBAL_ENABLE | ApicBaseFlags::X2APIC_ENABLE)
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EferFlags: u64 {
+        const SYSTEM_CALL_EXTENSIONS = 1 << 0;
+        const LONG_MODE_ENABLE = 1 << 8;
+        /// Read only, set by the CPU once paging is on in long mode
+        const LONG_MODE_ACTIVE = 1 << 10;
+        const NO_EXECUTE_ENABLE = 1 << 11;
+        const SECURE_VIRTUAL_MACHINE_ENABLE = 1 << 12;
+        const LONG_MODE_SEGMENT_LIMIT_ENABLE = 1 << 13;
+        const FAST_FXSAVE_FXRSTOR = 1 << 14;
+        const TRANSLATION_CACHE_EXTENSION = 1 << 15;
+    }
+}
+
+/// `IA32_EFER`
+pub struct Efer;
+
+impl Efer {
+    pub const MSR: Msr = Msr(IA32_EFER_MSR);
+
+    pub fn read() -> EferFlags {
+        // Can't be running 64 bit code without it
+        EferFlags::from_bits_retain(unsafe { Self::MSR.read() })
+    }
+
+    /// # Safety
+    /// Clearing long mode or NX under running code breaks it
+    pub unsafe fn write(flags: EferFlags) {
+        unsafe { Self::MSR.write(flags.bits()) };
+    }
+}
+
+/// `IA32_TSC_DEADLINE`: the local APIC timer fires once the TSC reaches the written value,
+/// 0 disarms it. Only exists with CPUID.01H:ECX.TSC_Deadline.
+pub struct TscDeadline;
+
+impl TscDeadline {
+    pub const MSR: Msr = Msr(IA32_TSC_DEADLINE_MSR);
+
+    /// # Safety
+    /// The CPU must support TSC deadline mode
+    pub unsafe fn read() -> u64 {
+        unsafe { Self::MSR.read() }
+    }
+
+    /// # Safety
+    /// The CPU must support TSC deadline mode
+    pub unsafe fn write(deadline: u64) {
+        unsafe { Self::MSR.write(deadline) };
+    }
+}
+
+macro_rules! segment_base {
+    ($(#[$attr:meta])* $name:ident, $msr:expr) => {
+        $(#[$attr])*
+        pub struct $name;
+
+        impl $name {
+            pub const MSR: Msr = Msr($msr);
+
+            pub fn read() -> u64 {
+                unsafe { Self::MSR.read() }
+            }
+
+            /// # Safety
+            /// `base` must be canonical, and nothing may rely on the old base anymore
+            pub unsafe fn write(base: u64) {
+                unsafe { Self::MSR.write(base) };
+            }
+        }
+    };
+}
+
+segment_base!(
+    /// `IA32_FS_BASE`
+    FsBase,
+    IA32_FS_BASE_MSR
+);
+segment_base!(
+    /// `IA32_GS_BASE`, the active GS base
+    GsBase,
+    IA32_GS_BASE_MSR
+);
+segment_base!(
+    /// `IA32_KERNEL_GS_BASE`, swapped with `IA32_GS_BASE` by `swapgs`
+    KernelGsBase,
+    IA32_KERNEL_GS_BASE_MSR
+);
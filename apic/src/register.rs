@@ -0,0 +1,41 @@
+use crate::consts::X2APIC_MSR_BASE;
+
+/// Local APIC registers by their xAPIC MMIO offset. In x2APIC mode the same register is the MSR
+/// `0x800 + offset / 16`, except for the ICR which is a single 64 bit MSR at `IcrLow`'s number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Register {
+    Id = 0x20,
+    Version = 0x30,
+    TaskPriority = 0x80,
+    ProcessorPriority = 0xA0,
+    Eoi = 0xB0,
+    LogicalDestination = 0xD0,
+    SpuriousVector = 0xF0,
+    ErrorStatus = 0x280,
+    LvtCmci = 0x2F0,
+    IcrLow = 0x300,
+    /// xAPIC only, the destination half of the ICR
+    IcrHigh = 0x310,
+    LvtTimer = 0x320,
+    LvtThermal = 0x330,
+    LvtPerformanceCounter = 0x340,
+    LvtLint0 = 0x350,
+    LvtLint1 = 0x360,
+    LvtError = 0x370,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivide = 0x3E0,
+    /// x2APIC only
+    SelfIpi = 0x3F0,
+}
+
+impl Register {
+    pub const fn offset(self) -> usize {
+        self as usize
+    }
+
+    pub const fn x2apic_msr(self) -> u32 {
+        X2APIC_MSR_BASE + (self as u32 >> 4)
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{
+    access::{CpuMsrs, MsrRegisters},
+    msr::{ApicBase, ApicBaseFlags},
+    Apic, ApicMode, Register,
+};
+
+/// Local APIC in x2APIC mode, every register is an MSR
+pub struct X2Apic<A = CpuMsrs> {
+    msrs: A,
+}
+
+impl X2Apic {
+    /// Switches the local APIC into x2APIC mode, a no-op if it already is
+    ///
+    /// # Safety
+    /// The CPU must support x2APIC, see `get_apic_available`. Any xAPIC handle stops working.
+    pub unsafe fn new() -> Self {
+        let mut apic_base = ApicBase::read();
+        if !apic_base.is_x2apic() {
+            //Enable first, x2APIC mode can only be entered from an enabled xAPIC
+            apic_base.flags |= ApicBaseFlags::GLOBAL_ENABLE;
+            unsafe { apic_base.write() };
+            apic_base.flags |= ApicBaseFlags::X2APIC_ENABLE;
+            unsafe { apic_base.write() };
+        }
+        Self::with_msrs(unsafe { CpuMsrs::new() })
+    }
+}
+
+impl<A: MsrRegisters> X2Apic<A> {
+    /// Leaves `IA32_APIC_BASE` alone, the APIC has to be in x2APIC mode already
+    pub fn with_msrs(msrs: A) -> Self {
+        Self { msrs }
+    }
+}
+
+impl<A: MsrRegisters> Apic for X2Apic<A> {
+    fn mode(&self) -> ApicMode {
+        ApicMode::X2Apic
+    }
+
+    fn read_register(&self, register: Register) -> u32 {
+        self.msrs.read(register.x2apic_msr()) as u32
+    }
+
+    fn write_register(&mut self, register: Register, value: u32) {
+        self.msrs.write(register.x2apic_msr(), value as u64);
+    }
+
+    fn write_icr(&mut self, icr: u64) {
+        self.msrs.write(Register::IcrLow.x2apic_msr(), icr);
+    }
+
+    fn id(&self) -> u32 {
+        //The full 32 bit x2APIC ID, not shifted like in xAPIC mode
+        self.read_register(Register::Id)
+    }
+
+    fn ipi_pending(&self) -> bool {
+        //The ICR has no delivery status bit, an IPI is on its way once the write retires
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::{Access::*, MockRegisters},
+        Destination, Ipi, Lvt, LvtEntry,
+    };
+
+    fn apic() -> (X2Apic<MockRegisters>, MockRegisters) {
+        let msrs = MockRegisters::default();
+        (X2Apic::with_msrs(msrs.clone()), msrs)
+    }
+
+    #[test]
+    fn id_is_the_full_register() {
+        let (apic, msrs) = apic();
+        msrs.set_read_only(0x802, 0x1_0203);
+        assert_eq!(apic.id(), 0x1_0203);
+    }
+
+    #[test]
+    fn ipi_is_a_single_msr_write() {
+        let (mut apic, msrs) = apic();
+        apic.send_ipi(Ipi::fixed(Destination::Physical(0x1_0203), 0x40));
+        assert_eq!(msrs.take_log(), [Write(0x830, 0x0001_0203_0000_4040)]);
+    }
+
+    #[test]
+    fn registers_map_to_msrs() {
+        let (mut apic, msrs) = apic();
+        apic.eoi();
+        apic.set_task_priority(0x20);
+        apic.set_lvt(LvtEntry::Lint1, Lvt::new(0).with_delivery_mode(crate::DeliveryMode::Nmi));
+        apic.error_status();
+        assert_eq!(
+            msrs.take_log(),
+            [Write(0x80B, 0), Write(0x808, 0x20), Write(0x836, 0x400), Write(0x828, 0), Read(0x828, 0)]
+        );
+    }
+
+    #[test]
+    fn never_pending() {
+        let (apic, msrs) = apic();
+        msrs.set_read_only(0x830, 1 << 12);
+        assert!(!apic.ipi_pending());
+        assert_eq!(msrs.take_log(), []);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{
+    access::{MmioPage, MmioRegisters},
+    msr::{ApicBase, ApicBaseFlags},
+    Apic, ApicMode, Register,
+};
+
+const XAPIC_ID_SHIFT: u32 = 24;
+
+/// Local APIC in xAPIC mode, registers are memory mapped at `IA32_APIC_BASE`
+pub struct XApic<M = MmioPage> {
+    registers: M,
+}
+
+impl XApic {
+    /// Globally enables the local APIC in xAPIC mode. `base` is the virtual address the
+    /// `IA32_APIC_BASE` page is mapped at, uncached.
+    ///
+    /// # Safety
+    /// `base` must map the xAPIC page of every core this handle is used on. The APIC must not be
+    /// in x2APIC mode, there is no way back from that without disabling it first.
+    pub unsafe fn new(base: *mut u8) -> Self {
+        let mut apic_base = ApicBase::read();
+        assert!(!apic_base.is_x2apic(), "local APIC already in x2APIC mode");
+        if !apic_base.is_enabled() {
+            apic_base.flags |= ApicBaseFlags::GLOBAL_ENABLE;
+            unsafe { apic_base.write() };
+        }
+        Self::with_registers(unsafe { MmioPage::new(base) })
+    }
+}
+
+impl<M: MmioRegisters> XApic<M> {
+    /// Leaves `IA32_APIC_BASE` alone, the APIC has to be enabled in xAPIC mode already
+    pub fn with_registers(registers: M) -> Self {
+        Self { registers }
+    }
+}
+
+impl<M: MmioRegisters> Apic for XApic<M> {
+    fn mode(&self) -> ApicMode {
+        ApicMode::XApic
+    }
+
+    fn read_register(&self, register: Register) -> u32 {
+        self.registers.read(register.offset())
+    }
+
+    fn write_register(&mut self, register: Register, value: u32) {
+        self.registers.write(register.offset(), value);
+    }
+
+    fn write_icr(&mut self, icr: u64) {
+        //Writing the low half sends it
+        self.write_register(Register::IcrHigh, (icr >> 32) as u32);
+        self.write_register(Register::IcrLow, icr as u32);
+    }
+
+    fn id(&self) -> u32 {
+        self.read_register(Register::Id) >> XAPIC_ID_SHIFT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::{Access::*, MockRegisters},
+        Destination, ErrorStatus, Ipi, Lvt, LvtEntry, TimerDivide, TimerMode,
+    };
+
+    fn apic() -> (XApic<MockRegisters>, MockRegisters) {
+        let registers = MockRegisters::default();
+        (XApic::with_registers(registers.clone()), registers)
+    }
+
+    #[test]
+    fn id_is_the_top_byte() {
+        let (apic, registers) = apic();
+        registers.set_read_only(0x20, 0x0700_0000);
+        assert_eq!(apic.id(), 7);
+        assert_eq!(registers.take_log(), [Read(0x20, 0x0700_0000)]);
+    }
+
+    #[test]
+    fn ipi_writes_destination_before_sending() {
+        let (mut apic, registers) = apic();
+        apic.send_ipi(Ipi::startup(Destination::Physical(3), 0x08));
+        assert_eq!(registers.take_log(), [Write(0x310, 0x0300_0000), Write(0x300, 0x4608)]);
+    }
+
+    #[test]
+    fn ipi_pending_reads_delivery_status() {
+        let (apic, registers) = apic();
+        registers.set(0x300, 0x1000);
+        assert!(apic.ipi_pending());
+        registers.set(0x300, 0x0500);
+        assert!(!apic.ipi_pending());
+    }
+
+    #[test]
+    fn error_status_is_latched_by_a_write() {
+        let (mut apic, registers) = apic();
+        registers.set_read_only(0x280, 0x40);
+        assert_eq!(apic.error_status(), ErrorStatus::RECEIVE_ILLEGAL_VECTOR);
+        assert_eq!(registers.take_log(), [Write(0x280, 0), Read(0x280, 0x40)]);
+    }
+
+    #[test]
+    fn timer_programming() {
+        let (mut apic, registers) = apic();
+        apic.set_timer_divide(TimerDivide::By16);
+        apic.set_lvt(LvtEntry::Timer, Lvt::new(0x30).with_timer_mode(TimerMode::Periodic));
+        apic.set_timer_initial_count(100_000);
+        assert_eq!(
+            registers.take_log(),
+            [Write(0x3E0, 0b0011), Write(0x320, 0x2_0030), Write(0x380, 100_000)]
+        );
+        assert_eq!(apic.timer_divide(), TimerDivide::By16);
+        assert_eq!(apic.lvt(LvtEntry::Timer).timer_mode(), TimerMode::Periodic);
+    }
+
+    #[test]
+    fn enable_and_eoi() {
+        let (mut apic, registers) = apic();
+        apic.enable(0xFF);
+        apic.eoi();
+        apic.disable();
+        assert_eq!(
+            registers.take_log(),
+            [Write(0xF0, 0x1FF), Write(0xB0, 0), Read(0xF0, 0x1FF), Write(0xF0, 0xFF)]
+        );
+    }
+}
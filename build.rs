@@ -1,4 +1,19 @@
-use std::path::PathBuf;
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+/// Section the kernel reserves for its symbol table, see `kernel/src/symbols.rs` for the layout
+const SYMBOL_SECTION: &str = ".unclad_symbols";
+const SYMBOL_MAGIC: &[u8; 4] = b"USYM";
+const SYMBOL_HEADER_LEN: usize = 16;
+const SYMBOL_ENTRY_LEN: usize = 24;
+const MAX_SYMBOL_NAME_LEN: usize = 128;
+/// Must match the target of the `kernel` artifact dependency
+const KERNEL_TARGET: &str = "x86_64-unknown-none";
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -6,6 +21,7 @@ fn main() {
     // set by cargo's artifact dependency feature, see
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
+    let kernel = embed_symbol_table(&kernel, &out_dir.join("kernel"));
 
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
@@ -18,4 +34,124 @@ fn main() {
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
-}
\ No newline at end of file
+    // the runner decodes trace records against the kernel ELF
+    println!("cargo:rustc-env=KERNEL_PATH={}", kernel.display());
+
+    build_test_kernels(&out_dir);
+}
+
+/// Every `test_*` binary of the kernel crate is a test case, booted by `tests/kernel.rs`.
+/// Binaries named `test_should_panic_*` are expected to exit with a failure code. They need the
+/// `kernel-tests` feature, which feature unification would hand to the main kernel as well if they
+/// were an artifact dependency too, so they are built by a cargo run of their own.
+fn build_test_kernels(out_dir: &Path) {
+    let manifest_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let mut names: Vec<String> = std::fs::read_dir(manifest_dir.join("kernel/src/bin"))
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            Some(name.strip_suffix(".rs")?.strip_prefix("test_")?.to_owned())
+        })
+        .collect();
+    names.sort();
+
+    let profile = std::env::var("PROFILE").unwrap();
+    let target_dir = out_dir.join("test-kernels");
+    let mut cargo = Command::new(std::env::var_os("CARGO").unwrap());
+    cargo
+        .current_dir(&manifest_dir)
+        .args(["build", "--package", "kernel", "--features", "kernel-tests", "--target", KERNEL_TARGET])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        // Meant for the build script itself, the kernel's flags come from the cargo config
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .stdout(Stdio::null());
+    if profile == "release" {
+        cargo.arg("--release");
+    }
+    for name in &names {
+        cargo.arg("--bin").arg(format!("test_{name}"));
+    }
+    let status = cargo.status().unwrap();
+    assert!(status.success(), "building the test kernels failed: {status}");
+
+    let mut cases_rs = String::new();
+    for name in names {
+        let kernel = target_dir.join(KERNEL_TARGET).join(&profile).join(format!("test_{name}"));
+        let kernel = embed_symbol_table(&kernel, &out_dir.join(format!("test_{name}")));
+        let bios_path = out_dir.join(format!("test_{name}-bios.img"));
+        bootloader::BiosBoot::new(&kernel).create_disk_image(&bios_path).unwrap();
+        let should_panic = name.starts_with("should_panic");
+        writeln!(cases_rs, "kernel_test!({name}, {:?}, {should_panic});", bios_path.display().to_string()).unwrap();
+    }
+    std::fs::write(out_dir.join("kernel_tests.rs"), cases_rs).unwrap();
+}
+
+/// Copies the kernel ELF to `out` with its reserved symbol section filled in
+fn embed_symbol_table(kernel: &Path, out: &Path) -> PathBuf {
+    let mut elf_bytes = std::fs::read(kernel).unwrap();
+    let elf = object::File::parse(&*elf_bytes).unwrap();
+    let Some((section_start, section_len)) = elf
+        .section_by_name(SYMBOL_SECTION)
+        .and_then(|section| section.file_range())
+    else {
+        println!("cargo:warning={} has no {SYMBOL_SECTION} section, not embedding symbols", kernel.display());
+        std::fs::write(out, &elf_bytes).unwrap();
+        return out.to_owned();
+    };
+
+    let mut symbols: Vec<(u64, u64, String)> = elf
+        .symbols()
+        .filter(|s| s.kind() == SymbolKind::Text && s.address() != 0)
+        .filter_map(|s| {
+            let mut name = format!("{:#}", rustc_demangle::demangle(s.name().ok()?));
+            if name.len() > MAX_SYMBOL_NAME_LEN {
+                let mut end = MAX_SYMBOL_NAME_LEN;
+                while !name.is_char_boundary(end) {
+                    end -= 1;
+                }
+                name.truncate(end);
+            }
+            Some((s.address(), s.size(), name))
+        })
+        .collect();
+    symbols.sort_by_key(|(address, _, _)| *address);
+    symbols.dedup_by_key(|(address, _, _)| *address);
+
+    // Drop symbols from the end until the table fits the reserved space
+    let capacity = section_len as usize;
+    let table_len = |symbols: &[(u64, u64, String)]| {
+        SYMBOL_HEADER_LEN + symbols.len() * SYMBOL_ENTRY_LEN + symbols.iter().map(|s| s.2.len()).sum::<usize>()
+    };
+    let total = symbols.len();
+    while table_len(&symbols) > capacity {
+        symbols.pop();
+    }
+    if symbols.len() != total {
+        println!("cargo:warning=kernel symbol table truncated to {} of {total} symbols", symbols.len());
+    }
+
+    let mut entries = Vec::with_capacity(symbols.len() * SYMBOL_ENTRY_LEN);
+    let mut strings = Vec::new();
+    for (address, size, name) in &symbols {
+        entries.extend_from_slice(&address.to_le_bytes());
+        entries.extend_from_slice(&(*size as u32).to_le_bytes());
+        entries.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&0u32.to_le_bytes());
+        strings.extend_from_slice(name.as_bytes());
+    }
+    let mut table = Vec::with_capacity(capacity);
+    table.extend_from_slice(SYMBOL_MAGIC);
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+    table.extend_from_slice(&entries);
+    table.extend_from_slice(&strings);
+    table.resize(capacity, 0);
+
+    let start = section_start as usize;
+    elf_bytes[start..start + capacity].copy_from_slice(&table);
+    std::fs::write(out, &elf_bytes).unwrap();
+    out.to_owned()
+}
@@ -47,6 +47,7 @@ fn assemble_x86_64_smp_boot() -> Result<()> {
 	let status = Command::new(&rust_lld)
 		.arg("-flavor")
 		.arg("gnu")
+		.arg("--image-base=0")
 		.arg("--section-start=.text=0x0000")
 		.arg("--oformat=binary")
 		.arg("-o")
@@ -1,161 +1,123 @@
-# Fully Relocatable x86_64 Long Mode Trampoline
+# Relocatable AP trampoline: real mode -> protected mode -> long mode -> ap_main
+#
+# Copied to a page below 1 MiB and entered through a SIPI, so CS holds the page's segment and IP
+# is 0. Everything is linked at 0, labels are offsets into the trampoline and get the physical
+# base added at runtime. The kernel patches the parameter block below before each SIPI, the
+# offsets are mirrored by the BOOT_OFFSET_* constants in multicore.rs.
 .intel_syntax noprefix
 
-.org 0
 .section .text
-.global trampoline_start
-.global trampoline_end
-.global entry_point
-.global stack_pointer
-.global page_table_l4
 .code16
 trampoline_start:
-    # Capture the current location using Intel syntax call
-    mov eax, cs
-    call get_rip
+    jmp real_mode_entry
 
 .align 8
-entry_point: .8byte 0  # 64-bit entry point to jump to
-stack_pointer: .8byte 0  # 64-bit stack pointer to use
-page_table_l4: .4byte 0  # Physical address of PML4 table
-jump_holder: .4byte 0  # Placeholder for jump address
-    
-.set ip_offset, get_rip - trampoline_start
-get_rip:
-    # EAX contains reset vector
-    # EBX will be used to store the base address
-    # Calculate base address
+entry_point: .8byte 0     # 0x08: ap_main
+stack_pointer: .8byte 0   # 0x10: top of this AP's stack
+page_table_l4: .8byte 0   # 0x18: PML4 of the temporary low tables, must be below 4 GiB
+cpu_id: .4byte 0          # 0x20: passed to ap_main
+ap_started: .4byte 0      # 0x24: set by the AP as soon as it runs, tells the BSP the SIPI landed
+kernel_cr3: .8byte 0      # 0x28: the kernel's PML4, passed to ap_main which switches to it
+
+real_mode_entry:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    mov dword ptr [ap_started], 1
 
+    # EBX = physical base of the trampoline, kept in EBX/RBX until ap_main
     xor ebx, ebx
-    mov ebx, eax
+    mov bx, ax
     shl ebx, 4
-    xor eax, eax
-
-    mov [base_address], ebx      # Store base address for later use
-
-    # Disable interrupts
-    cli
-
-    # A20 line enable
-    in al, 0x92
-    or al, 2
-    out 0x92, al
 
-    # Dynamically calculate GDT descriptor
-    lea eax, [ebx + gdt_descriptor]
-    mov [gdt_dynamic_address], eax
+    # Relocate the GDT pointer and both far jump targets
+    lea eax, [ebx + gdt_start]
+    mov dword ptr [gdt_pointer + 2], eax
+    lea eax, [ebx + protected_mode_entry]
+    mov dword ptr [protected_mode_target], eax
+    lea eax, [ebx + long_mode_entry]
+    mov dword ptr [long_mode_target], eax
 
-    # Load dynamically calculated GDT
-    lgdt [gdt_dynamic_address]
+    lgdt [gdt_pointer]
 
-    # Enable Protected Mode
+    # Protected mode
     mov eax, cr0
     or eax, 1
     mov cr0, eax
 
-    # Relative far jump to protected mode
-
-    jmp cs:protected_mode_entry
-;     call compute_far_jump
-    
-; # Dynamic far jump computation
-; compute_far_jump:
-;     pop ax                      # Return address
-;     lea eax, [protected_mode_entry]
+    # jmp 0x08:protected_mode_target, 32 bit offset
+    .byte 0x66, 0xea
+protected_mode_target: .4byte 0
+    .2byte 0x08
 
 .code32
 protected_mode_entry:
-    # Set up segments
-    hlt
-    hlt
-
-    # BUG: Something is wrong here
     mov ax, 0x10
     mov ds, ax
     mov es, ax
-    mov fs, ax
-    mov gs, ax
     mov ss, ax
 
-    hlt
-    hlt
-    # Enable PAE
+    # PAE
     mov eax, cr4
-    or eax, (1 << 5)  # PAE bit
+    or eax, 1 << 5
     mov cr4, eax
 
-    # BUG: Could have a problem here, jumps after paging could be incorrect
-    mov eax, cr0
-    or eax, 0x80000000   # Paging Enable
-    mov cr0, eax
+    mov eax, [ebx + page_table_l4]
+    mov cr3, eax
 
-    hlt
-    hlt
+    # EFER: long mode enable, no-execute enable (the kernel's tables use NX)
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
 
-    # Prepare for long mode entry
-    
-    jmp cs:long_mode_entry
+    # Paging and write protect, activates long mode
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
 
-compute_long_mode_jump:
-    pop eax                     # Return address
-    push 0x08              # Code segment selector
-    mov ebx, [base_address]
-    lea eax, [ebx + long_mode_entry]
-    push eax
-    retf                        # Far return performs the jump
+    # jmp 0x18:long_mode_target
+    .byte 0xea
+long_mode_target: .4byte 0
+    .2byte 0x18
 
 .code64
 long_mode_entry:
-    hlt
-    hlt 
-    hlt
-    # Enable long mode MSR
-    mov ecx, 0xC0000080  # EFER MSR
-    rdmsr
-    or eax, (1 << 8)     # Long Mode Enable
-    wrmsr
-
-    # Enable paging
-
-    # Set up 64-bit segments
-    xor rax, rax
     mov ax, 0x10
     mov ds, ax
     mov es, ax
+    mov ss, ax
+    xor eax, eax
     mov fs, ax
     mov gs, ax
-    mov ss, ax
-
-    # Load configuration
-    mov rsp, [ebx + stack_pointer]
 
-    # Maybe LEA entry point?
-    lea rax, [ebx + entry_point]
+    # Upper halves are undefined after the switch
+    mov ebx, ebx
+    mov rsp, [rbx + stack_pointer]
+    mov edi, [rbx + cpu_id]
+    mov rsi, [rbx + kernel_cr3]
+    mov rax, [rbx + entry_point]
+    # Ends the frame pointer chain for backtraces
+    xor ebp, ebp
     call rax
 
-    # Halt if entry point returns
-
+    # ap_main never returns
+halt:
     cli
     hlt
+    jmp halt
 
-.align 8
-base_address: .2byte 0
-
-# Dynamically computed GDT
-
+.align 16
 gdt_start:
-.quad 0x0000000000000000  # Null descriptor
-.quad 0x00AF9A000000FFFF  # 64-bit Code Segment
-.quad 0x00AF92000000FFFF  # 64-bit Data Segment
+    .8byte 0x0000000000000000  # Null descriptor
+    .8byte 0x00CF9A000000FFFF  # 0x08: 32-bit code
+    .8byte 0x00CF92000000FFFF  # 0x10: data
+    .8byte 0x00AF9A000000FFFF  # 0x18: 64-bit code
 gdt_end:
 
-# Dynamic GDT descriptor
-gdt_dynamic_address: .4byte 0
-gdt_descriptor:
-    .word gdt_end - gdt_start - 1  # GDT size
-    .quad 0                        # Placeholder for base (will be filled dynamically)
-
-small_stack:
-    .space 4096                   # 4KB stack space
+gdt_pointer:
+    .2byte gdt_end - gdt_start - 1
+    .4byte 0                   # Physical base, patched at runtime
 
-trampoline_end:
\ No newline at end of file
+trampoline_end:
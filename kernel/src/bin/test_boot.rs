@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+
+// Boots through the full BSP init and exits, anything that faults or hangs on the way fails
+
+use kernel::qemu::{QemuExitCode, exit_qemu, set_exit_on_panic};
+
+bootloader_api::entry_point!(kernel_main, config = &kernel::CONFIG);
+
+fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    set_exit_on_panic();
+    let acpi = kernel::init(boot_info);
+    assert!(acpi.platform_info().is_ok(), "ACPI platform info unavailable");
+    log::info!("Boot test finished");
+    exit_qemu(QemuExitCode::Success);
+}
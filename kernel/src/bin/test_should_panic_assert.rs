@@ -0,0 +1,19 @@
+#![no_std]
+#![no_main]
+
+// Checks that a panic after init is reported to the host as a failure instead of hanging
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use kernel::qemu::{QemuExitCode, exit_qemu, set_exit_on_panic};
+
+bootloader_api::entry_point!(kernel_main, config = &kernel::CONFIG);
+
+fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    set_exit_on_panic();
+    kernel::init(boot_info);
+    let values: Vec<u64> = (0..16).collect();
+    assert_eq!(values.iter().sum::<u64>(), 0, "expected panic");
+    exit_qemu(QemuExitCode::Success);
+}
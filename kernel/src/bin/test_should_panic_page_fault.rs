@@ -0,0 +1,17 @@
+#![no_std]
+#![no_main]
+
+// Checks that a page fault goes through the fatal exception path and fails the run
+
+use kernel::qemu::{QemuExitCode, exit_qemu, set_exit_on_panic};
+
+bootloader_api::entry_point!(kernel_main, config = &kernel::CONFIG);
+
+fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    set_exit_on_panic();
+    kernel::init(boot_info);
+    // Canonical but far away from anything the bootloader or the kernel maps
+    let unmapped = 0x0000_7fff_dead_0000 as *const u64;
+    let _ = unsafe { core::ptr::read_volatile(unmapped) };
+    exit_qemu(QemuExitCode::Success);
+}
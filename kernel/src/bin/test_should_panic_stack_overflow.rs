@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+// Checks that running off the end of the kernel stack is reported instead of triple faulting
+
+use core::hint::black_box;
+
+use kernel::qemu::{QemuExitCode, exit_qemu, set_exit_on_panic};
+
+bootloader_api::entry_point!(kernel_main, config = &kernel::CONFIG);
+
+fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    set_exit_on_panic();
+    kernel::init(boot_info);
+    recurse(0);
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[allow(unconditional_recursion)]
+fn recurse(depth: u64) -> u64 {
+    let frame = black_box([depth; 64]);
+    recurse(depth + 1) + frame[0]
+}
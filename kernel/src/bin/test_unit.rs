@@ -0,0 +1,12 @@
+#![no_std]
+#![no_main]
+
+// Runs the in-kernel unit tests registered in `kernel::testing`
+
+bootloader_api::entry_point!(kernel_main, config = &kernel::CONFIG);
+
+fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    kernel::qemu::set_exit_on_panic();
+    kernel::init(boot_info);
+    kernel::testing::run_all();
+}
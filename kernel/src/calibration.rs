@@ -0,0 +1,170 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{hpet, pit, pm_timer};
+
+// Fixed frequency references that the free running counters (TSC, local APIC timer) are measured
+// against. Every reference runs a window and calls back right at its edges, so the counter being
+// calibrated is sampled as close to them as possible.
+
+/// Known frequency clocks, `best` picks the most precise one the machine has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference {
+    Pit,
+    /// Only once `pm_timer::init` found one
+    PmTimer,
+    /// Only once `hpet::init` found one
+    Hpet,
+}
+
+impl Reference {
+    pub fn best() -> Self {
+        if hpet::is_present() {
+            Reference::Hpet
+        } else if pm_timer::is_present() {
+            Reference::PmTimer
+        } else {
+            Reference::Pit
+        }
+    }
+
+    /// Runs a window of about `us` microseconds, calling `started` and `ended` at its edges.
+    /// Returns the exact length of the window in nanoseconds.
+    pub fn measure(self, us: u64, started: impl FnOnce(), ended: impl FnOnce()) -> u64 {
+        match self {
+            Reference::Pit => {
+                let ticks = pit::ticks_for_us(us);
+                pit::measure(ticks, started, ended);
+                ticks as u64 * 1_000_000_000 / pit::PIT_FREQUENCY
+            }
+            Reference::Hpet => {
+                hpet::ticks_to_ns(poll_window(hpet::ns_to_ticks(us * 1000), hpet::counter, started, ended))
+            }
+            Reference::PmTimer => {
+                pm_timer::ticks_to_ns(poll_window(pm_timer::ns_to_ticks(us * 1000), pm_timer::counter, started, ended))
+            }
+        }
+    }
+}
+
+/// Polls `counter` until at least `ticks` passed, calling `started` and `ended` at the edges.
+/// Returns how many ticks the window really lasted.
+fn poll_window(ticks: u64, counter: impl Fn() -> u64, started: impl FnOnce(), ended: impl FnOnce()) -> u64 {
+    let start = counter();
+    started();
+    let mut now = start;
+    while now - start < ticks {
+        now = counter();
+    }
+    ended();
+    now - start
+}
+
+/// A hardware counter narrower than 64 bits, extended in software by counting its wraps. Only
+/// holds as long as something reads it at least once per wrap.
+pub(crate) struct ExtendedCounter {
+    /// Last value `extend` returned
+    last: AtomicU64,
+}
+
+impl ExtendedCounter {
+    pub(crate) const fn new() -> Self {
+        Self { last: AtomicU64::new(0) }
+    }
+
+    /// Starts counting from `raw`
+    pub(crate) fn reset(&self, raw: u64) {
+        self.last.store(raw, Ordering::Relaxed);
+    }
+
+    /// Extends a `bits` wide reading `raw` to 64 bits, never going backwards
+    pub(crate) fn extend(&self, raw: u64, bits: u32) -> u64 {
+        let mut last = self.last.load(Ordering::Relaxed);
+        loop {
+            let now = extend(last, raw, bits);
+            match self.last.compare_exchange_weak(last, now, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return now,
+                // Another core already got further, it can only have seen the same or a later raw value
+                Err(newer) if newer >= now => return newer,
+                Err(newer) => last = newer,
+            }
+        }
+    }
+}
+
+const fn mask(bits: u32) -> u64 {
+    (1 << bits) - 1
+}
+
+/// Puts the low `bits` of `raw` on top of `last`, counting one wrap if they went backwards
+const fn extend(last: u64, raw: u64, bits: u32) -> u64 {
+    let now = (last & !mask(bits)) | raw;
+    if now < last { now + (1 << bits) } else { now }
+}
+
+/// Frequency of an up counting `counter` in Hz over `rounds` windows of `us` microseconds. Takes
+/// the lowest reading, a late edge from an SMI or an emulation hiccup only ever adds counts.
+pub fn frequency(reference: Reference, us: u64, rounds: usize, counter: impl Fn() -> u64) -> u64 {
+    (0..rounds)
+        .map(|_| {
+            let (mut start, mut end) = (0, 0);
+            let window_ns = reference.measure(us, || start = counter(), || end = counter());
+            ((end - start) as u128 * 1_000_000_000 / window_ns as u128) as u64
+        })
+        .min()
+        .expect("no calibration rounds")
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{testing::{self, test_cases}, tsc};
+
+    test_cases![
+        extend_counts_wraps,
+        pit_window_is_exact,
+        hpet_window_covers_request,
+        pm_timer_window_covers_request,
+        frequency_scales_to_hz,
+    ];
+
+    fn extend_counts_wraps() {
+        assert_eq!(extend(0, 5, 24), 5);
+        assert_eq!(extend(0xFF_FFF0, 0x10, 24), 0x100_0010);
+        assert_eq!(extend(0x300_0010, 0x20, 24), 0x300_0020);
+        assert_eq!(extend(0xFFFF_FFFF, 0, 32), 0x1_0000_0000);
+    }
+
+    fn pit_window_is_exact() {
+        let (mut start, mut end) = (0, 0);
+        let window_ns = Reference::Pit.measure(1_000, || start = tsc::read(), || end = tsc::read());
+        assert_eq!(window_ns, pit::ticks_for_us(1_000) as u64 * 1_000_000_000 / pit::PIT_FREQUENCY);
+        assert!(end > start);
+    }
+
+    fn hpet_window_covers_request() {
+        if !hpet::is_present() {
+            return testing::skip("no HPET");
+        }
+        assert_eq!(Reference::best(), Reference::Hpet);
+        let (mut start, mut end) = (0, 0);
+        let window_ns = Reference::Hpet.measure(1_000, || start = tsc::read(), || end = tsc::read());
+        assert!(window_ns >= 1_000_000, "{} ns window", window_ns);
+        assert!(end > start);
+    }
+
+    fn pm_timer_window_covers_request() {
+        if !pm_timer::is_present() {
+            return testing::skip("no ACPI PM timer");
+        }
+        let (mut start, mut end) = (0, 0);
+        let window_ns = Reference::PmTimer.measure(1_000, || start = tsc::read(), || end = tsc::read());
+        assert!(window_ns >= 1_000_000, "{} ns window", window_ns);
+        let tsc_ns = tsc::cycles_to_ns(end - start);
+        assert!(testing::timing_matches(window_ns, tsc_ns), "{} ns window, TSC {} ns", window_ns, tsc_ns);
+    }
+
+    fn frequency_scales_to_hz() {
+        let hz = frequency(Reference::best(), 2_000, 2, tsc::read);
+        assert!(testing::timing_matches(hz, tsc::frequency()), "{} Hz", hz);
+    }
+}
@@ -0,0 +1,309 @@
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use apic::{Apic, Destination, Ipi};
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    instructions::{hlt, interrupts},
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+use crate::{
+    logger::LOGGER,
+    memory::{get_active_opt, UncladCustomPageFlags},
+    multicore,
+    percpu::{self, core_index},
+    qemu::{self, QemuExitCode},
+    serial::COM1,
+    stack::STACK_REFS,
+    symbols::Resolve,
+    tsc, PHYS_OFFSET,
+};
+
+// Machine readable crash report, written straight to COM1 between `~CRASH BEGIN` and `~CRASH END`
+// as `key: value` lines. The host runner picks it up and symbolizes every `rip`/`frame` address
+// against the kernel ELF, subtracting `image_offset` first.
+
+pub const BEGIN_MARKER: &str = "~CRASH BEGIN";
+pub const END_MARKER: &str = "~CRASH END";
+const MAX_FRAMES: usize = 32;
+/// For the other cores to take the NMI `fatal` stops them with
+const STOP_TIMEOUT_US: u64 = 10_000;
+
+/// Virtual address the bootloader loaded the kernel image at, 0 for non relocated kernels
+pub(crate) static KERNEL_IMAGE_OFFSET: OnceCell<u64> = OnceCell::uninit();
+/// Set by the first core into `fatal`, every other core halts in its NMI handler from then on
+static HALTING: AtomicBool = AtomicBool::new(false);
+/// Cores halted in their NMI handler
+static HALTED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct GeneralRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl GeneralRegisters {
+    /// Snapshot of the caller's registers, the one holding the output pointer reads as that pointer
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut regs = Self::default();
+        unsafe {
+            asm!(
+                "mov [{0} + 0x00], rax",
+                "mov [{0} + 0x08], rbx",
+                "mov [{0} + 0x10], rcx",
+                "mov [{0} + 0x18], rdx",
+                "mov [{0} + 0x20], rsi",
+                "mov [{0} + 0x28], rdi",
+                "mov [{0} + 0x30], rbp",
+                "mov [{0} + 0x38], rsp",
+                "mov [{0} + 0x40], r8",
+                "mov [{0} + 0x48], r9",
+                "mov [{0} + 0x50], r10",
+                "mov [{0} + 0x58], r11",
+                "mov [{0} + 0x60], r12",
+                "mov [{0} + 0x68], r13",
+                "mov [{0} + 0x70], r14",
+                "mov [{0} + 0x78], r15",
+                in(reg) &mut regs as *mut Self,
+                options(nostack, preserves_flags),
+            );
+        }
+        regs
+    }
+
+    fn named(&self) -> [(&'static str, u64); 16] {
+        [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("rsp", self.rsp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+        ]
+    }
+}
+
+/// Where the crash happened, the panic handler's own state or a faulting exception frame
+#[derive(Debug, Clone, Copy)]
+pub struct CrashContext {
+    pub rip: u64,
+    pub registers: GeneralRegisters,
+    pub register_source: RegisterSource,
+}
+
+/// Whose general registers a `CrashContext` holds, the report labels them accordingly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterSource {
+    /// Captured by the panic handler
+    PanicHandler,
+    /// The exception handler's own, only `rsp` and `rbp` are the interrupted code's
+    ExceptionHandler,
+}
+
+impl RegisterSource {
+    fn label(self) -> &'static str {
+        match self {
+            RegisterSource::PanicHandler => "panic handler",
+            RegisterSource::ExceptionHandler => "exception handler, except rsp and rbp",
+        }
+    }
+}
+
+pub(crate) fn is_mapped(addr: u64) -> bool {
+    let Some(offset) = PHYS_OFFSET.get() else {
+        return false;
+    };
+    let mapper = unsafe { get_active_opt(VirtAddr::new(*offset as u64)) };
+    // Guard pages translate fine but aren't present
+    match VirtAddr::try_new(addr).map(|addr| mapper.translate(addr)) {
+        Ok(TranslateResult::Mapped { flags, .. }) => flags.contains(PageTableFlags::PRESENT),
+        _ => false,
+    }
+}
+
+/// Follows the saved `rbp` chain, stops at the first frame that isn't mapped or doesn't move up
+pub fn walk_frames(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 || !is_mapped(rbp) || !is_mapped(rbp + 8) {
+            return;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            return;
+        }
+        f(ret);
+        if next <= rbp {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+// Keeps multi-line messages on one `message:` line
+struct EscapeNewlines<'a, W: Write>(&'a mut W);
+
+impl<W: Write> Write for EscapeNewlines<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i != 0 {
+                self.0.write_str("\\n")?;
+            }
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_stack_ref(out: &mut impl Write, rsp: u64) -> fmt::Result {
+    let Some(offset) = PHYS_OFFSET.get() else {
+        return writeln!(out, "stack_ref: unknown");
+    };
+    let mapper = unsafe { get_active_opt(VirtAddr::new(*offset as u64)) };
+    let stack_ref = match VirtAddr::try_new(rsp).map(|rsp| mapper.translate(rsp)) {
+        Ok(TranslateResult::Mapped { flags, .. }) => flags.stack_ref(),
+        _ => None,
+    };
+    match stack_ref {
+        Some(stack_ref) => {
+            let stack = unsafe { STACK_REFS.get(stack_ref.as_u16() as usize) };
+            match stack {
+                Some(stack) => writeln!(
+                    out,
+                    "stack_ref: {} base={:#x} size={:#x}",
+                    stack_ref.as_u16(),
+                    stack.stack_base.as_u64(),
+                    stack.max_stack_size
+                ),
+                None => writeln!(out, "stack_ref: {}", stack_ref.as_u16()),
+            }
+        }
+        None => writeln!(out, "stack_ref: none"),
+    }
+}
+
+fn write_report(out: &mut impl Write, message: &dyn fmt::Display, context: &CrashContext) -> fmt::Result {
+    writeln!(out, "{}", BEGIN_MARKER)?;
+    write!(out, "message: ")?;
+    write!(EscapeNewlines(&mut *out), "{}", message)?;
+    writeln!(out)?;
+    writeln!(out, "core: {}", core_index())?;
+    writeln!(out, "image_offset: {:#x}", KERNEL_IMAGE_OFFSET.get().copied().unwrap_or(0))?;
+    writeln!(out, "rip: {:#x}", context.rip)?;
+    writeln!(out, "registers: {}", context.register_source.label())?;
+    for (name, value) in context.registers.named() {
+        writeln!(out, "{}: {:#x}", name, value)?;
+    }
+    writeln!(out, "cr0: {:#x}", Cr0::read_raw())?;
+    writeln!(out, "cr2: {:#x}", Cr2::read_raw())?;
+    writeln!(out, "cr3: {:#x}", Cr3::read_raw().0.start_address().as_u64())?;
+    writeln!(out, "cr4: {:#x}", Cr4::read_raw())?;
+    write_stack_ref(out, context.registers.rsp)?;
+    let mut result = Ok(());
+    walk_frames(context.registers.rbp, |ret| {
+        if result.is_ok() {
+            result = writeln!(out, "frame: {:#x}", ret);
+        }
+    });
+    result?;
+    writeln!(out, "{}", END_MARKER)
+}
+
+/// Writes the crash report for `context` to COM1, bypassing the logger and its levels
+pub fn report(message: &dyn fmt::Display, context: &CrashContext) {
+    let mut port = COM1.lock();
+    let _ = write_report(&mut *port, message, context);
+    port.flush();
+}
+
+/// Logs the backtrace resolved against the embedded symbol table, for when no host tool is around
+pub fn log_backtrace(context: &CrashContext) {
+    log::error!("  at   {:#018x} {}", context.rip, Resolve(context.rip));
+    let mut depth = 0;
+    walk_frames(context.registers.rbp, |ret| {
+        // Return addresses point after the call, step back into it for the lookup
+        log::error!("  #{:<3} {:#018x} {}", depth, ret, Resolve(ret - 1));
+        depth += 1;
+    });
+}
+
+/// Sends every other core an NMI that halts it for good, returns whether they all took it. Only
+/// the first core to crash gets here, any later one halts right away and waits for its NMI.
+fn stop_other_cores() -> bool {
+    if HALTING.swap(true, Ordering::AcqRel) {
+        loop {
+            hlt();
+        }
+    }
+    let others = multicore::online_aps();
+    if others == 0 {
+        return true;
+    }
+    // Whoever had the local APIC when this core went down never gets it back
+    let sent = percpu::current().with_local_apic(|apic| apic.send_ipi(Ipi::nmi(Destination::AllExcludingSelf)));
+    sent.is_some() && tsc::wait_until(STOP_TIMEOUT_US, || HALTED.load(Ordering::Acquire) >= others)
+}
+
+/// Called first thing in the NMI handler, halts the core for good once another one is in `fatal`
+pub(crate) fn halt_if_crashing() {
+    if !HALTING.load(Ordering::Acquire) {
+        return;
+    }
+    HALTED.fetch_add(1, Ordering::AcqRel);
+    // NMIs stay blocked until the handler returns, nothing wakes the core anymore
+    loop {
+        hlt();
+    }
+}
+
+/// The one way out for panics and unrecoverable exceptions: logs, reports and stops the core
+pub fn fatal(message: &dyn fmt::Display, context: &CrashContext) -> ! {
+    interrupts::disable();
+    if stop_other_cores() {
+        // Every other core is halted, whatever held the logger isn't coming back
+        unsafe { LOGGER.force_unlock() };
+    }
+    crate::trace::try_drain();
+    log::error!("Kernel panic: {}", message);
+    report(message, context);
+    log_backtrace(context);
+    #[cfg(feature = "kernel-tests")]
+    crate::testing::report_failure(message);
+    if qemu::exit_on_panic() {
+        qemu::exit_qemu(QemuExitCode::Failed);
+    }
+    loop {
+        hlt();
+    }
+}
@@ -0,0 +1,422 @@
+use core::{cell::Cell, fmt};
+
+use log::Level;
+use x86_64::{
+    VirtAddr,
+    instructions::hlt,
+    registers::control::Cr2,
+    set_general_handler,
+    structures::idt::{DescriptorTable, InterruptStackFrame, PageFaultErrorCode, SelectorErrorCode},
+};
+
+use crate::{
+    crash::{self, is_mapped, CrashContext, GeneralRegisters, RegisterSource},
+    gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX, PAGE_FAULT_IST_INDEX},
+    logger::LOGGER,
+    percpu::{core_index, percpu},
+    stack::{grow_stack, guard_page_stack, Stack},
+    symbols::Resolve,
+    IDT,
+};
+
+// CPU exception handlers. Debug traps and NMIs are logged and resumed, except for the NMI
+// `crash::fatal` halts the other cores with. Every other exception is unrecoverable and ends in
+// `crash::fatal` with a report built from the faulting frame. #DF, NMI, #MC and #PF run on their
+// own IST stack, see `gdt`. Page faults in the reserved part of a stack aren't errors, they map the
+// page and resume. A #PF while one is being handled lands on the same IST stack over the first
+// one's frame, so it is fatal.
+
+const UD_DUMP_LEN: usize = 16;
+/// Where the local APIC sends interrupts it had to drop, see `multicore::init_local_apic`
+pub(crate) const SPURIOUS_VECTOR: u8 = 0xFF;
+
+percpu! {
+    /// Page faults the executing core is in the middle of handling
+    static PAGE_FAULT_DEPTH: Cell<u32> = Cell::new(0);
+}
+
+/// Installs the exception handlers, routes the remaining vectors to a logging handler and loads the IDT
+pub(crate) fn init_idt() {
+    let idt = unsafe { &mut IDT };
+    set_general_handler!(idt, unexpected_interrupt, 32..=255);
+    idt[SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt);
+    idt.divide_error.set_handler_fn(divide_error);
+    idt.debug.set_handler_fn(debug);
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_fn(non_maskable_interrupt)
+            .set_stack_index(NMI_IST_INDEX);
+    }
+    idt.breakpoint.set_handler_fn(breakpoint);
+    idt.overflow.set_handler_fn(overflow);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode);
+    idt.device_not_available.set_handler_fn(device_not_available);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss);
+    idt.segment_not_present.set_handler_fn(segment_not_present);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault);
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(page_fault)
+            .set_stack_index(PAGE_FAULT_IST_INDEX);
+    }
+    idt.x87_floating_point.set_handler_fn(x87_floating_point);
+    idt.alignment_check.set_handler_fn(alignment_check);
+    unsafe {
+        idt.machine_check
+            .set_handler_fn(machine_check)
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+    }
+    idt.simd_floating_point.set_handler_fn(simd_floating_point);
+    idt.virtualization.set_handler_fn(virtualization);
+    idt.cp_protection_exception.set_handler_fn(cp_protection_exception);
+    idt.hv_injection_exception.set_handler_fn(hv_injection_exception);
+    idt.vmm_communication_exception.set_handler_fn(vmm_communication_exception);
+    idt.security_exception.set_handler_fn(security_exception);
+    load_idt();
+}
+
+/// Loads the shared IDT on the executing core, its TSS must already be loaded for the IST entries
+pub(crate) fn load_idt() {
+    unsafe { IDT.load() };
+}
+
+/// Crash context of the interrupted code. Must be inlined into the handler, the saved `rbp` is
+/// read from the handler's own frame. The other general registers are the handler's, the
+/// interrupt ABI doesn't say where it saved the interrupted ones, and the report says so.
+#[inline(always)]
+fn fault_context(frame: &InterruptStackFrame) -> CrashContext {
+    let mut registers = GeneralRegisters::capture();
+    // The handler prologue pushed the interrupted `rbp` right where its own `rbp` points
+    registers.rbp = if is_mapped(registers.rbp) {
+        unsafe { *(registers.rbp as *const u64) }
+    } else {
+        0
+    };
+    registers.rsp = frame.stack_pointer.as_u64();
+    CrashContext {
+        rip: frame.instruction_pointer.as_u64(),
+        registers,
+        register_source: RegisterSource::ExceptionHandler,
+    }
+}
+
+/// `<address> (<symbol>)`
+struct Location(u64);
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x} ({})", self.0, Resolve(self.0))
+    }
+}
+
+/// Decoded error code of #TS, #NP, #SS and #GP
+struct SelectorError(u64);
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = SelectorErrorCode::new_truncate(self.0);
+        if code.is_null() {
+            return write!(f, "error code 0 (not selector related)");
+        }
+        let table = match code.descriptor_table() {
+            DescriptorTable::Gdt => "GDT",
+            DescriptorTable::Idt => "IDT",
+            DescriptorTable::Ldt => "LDT",
+        };
+        write!(f, "error code {:#x}: {} index {}", self.0, table, code.index())?;
+        if code.external() {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// Decoded #PF error code
+struct PageFaultReason(PageFaultErrorCode);
+
+impl fmt::Display for PageFaultReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let cause = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "page not present"
+        };
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "supervisor" };
+        write!(f, "{} {}, {} (error code {:#x})", mode, access, cause, code.bits())?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in a paging entry")?;
+        }
+        if code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            write!(f, ", protection key")?;
+        }
+        if code.contains(PageFaultErrorCode::SHADOW_STACK) {
+            write!(f, ", shadow stack")?;
+        }
+        Ok(())
+    }
+}
+
+/// `stack overflow on stack #N (core X, size Y)`
+struct StackOverflow(Stack);
+
+impl fmt::Display for StackOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "stack overflow on stack #{} (core {}, size {:#x})",
+            self.0.stack_ref.as_u16(),
+            core_index(),
+            self.0.max_stack_size
+        )
+    }
+}
+
+/// Ends in a stack overflow report if `address` is in a stack's guard page
+#[inline(always)]
+fn check_stack_overflow(address: u64, context: &CrashContext) {
+    if let Some(stack) = VirtAddr::try_new(address).ok().and_then(guard_page_stack) {
+        crash::fatal(
+            &format_args!("{} accessing {:#x} at {}", StackOverflow(stack), address, Location(context.rip)),
+            context,
+        );
+    }
+}
+
+/// Hex dump of the instruction bytes at `rip`, as far as they are mapped
+struct InstructionBytes(u64);
+
+impl fmt::Display for InstructionBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for i in 0..UD_DUMP_LEN as u64 {
+            let addr = self.0.wrapping_add(i);
+            if !is_mapped(addr) {
+                if i == 0 {
+                    write!(f, "<unmapped>")?;
+                }
+                break;
+            }
+            if i != 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02x}", unsafe { *(addr as *const u8) })?;
+        }
+        Ok(())
+    }
+}
+
+macro_rules! fatal_exception {
+    ($name:ident, $description:literal) => {
+        extern "x86-interrupt" fn $name(frame: InterruptStackFrame) {
+            let context = fault_context(&frame);
+            crash::fatal(&format_args!(concat!($description, " at {}"), Location(context.rip)), &context);
+        }
+    };
+    ($name:ident, $description:literal, error_code) => {
+        extern "x86-interrupt" fn $name(frame: InterruptStackFrame, error_code: u64) {
+            let context = fault_context(&frame);
+            crash::fatal(
+                &format_args!(concat!($description, " at {}, error code {:#x}"), Location(context.rip), error_code),
+                &context,
+            );
+        }
+    };
+}
+
+fatal_exception!(divide_error, "divide error (#DE)");
+fatal_exception!(overflow, "overflow (#OF)");
+fatal_exception!(bound_range_exceeded, "bound range exceeded (#BR)");
+fatal_exception!(device_not_available, "device not available (#NM)");
+fatal_exception!(x87_floating_point, "x87 floating point exception (#MF)");
+fatal_exception!(simd_floating_point, "SIMD floating point exception (#XM)");
+fatal_exception!(virtualization, "virtualization exception (#VE)");
+fatal_exception!(hv_injection_exception, "hypervisor injection exception (#HV)");
+fatal_exception!(cp_protection_exception, "control protection exception (#CP)", error_code);
+fatal_exception!(vmm_communication_exception, "VMM communication exception (#VC)", error_code);
+fatal_exception!(security_exception, "security exception (#SX)", error_code);
+
+extern "x86-interrupt" fn invalid_opcode(frame: InterruptStackFrame) {
+    let context = fault_context(&frame);
+    crash::fatal(
+        &format_args!(
+            "invalid opcode (#UD) at {}, bytes: {}",
+            Location(context.rip),
+            InstructionBytes(context.rip)
+        ),
+        &context,
+    );
+}
+
+extern "x86-interrupt" fn alignment_check(frame: InterruptStackFrame, error_code: u64) {
+    let context = fault_context(&frame);
+    crash::fatal(
+        &format_args!(
+            "alignment check (#AC) at {}, error code {:#x}, rsp {:#x}",
+            Location(context.rip),
+            error_code,
+            context.registers.rsp
+        ),
+        &context,
+    );
+}
+
+extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let address = Cr2::read_raw();
+    let depth = PAGE_FAULT_DEPTH.get();
+    match depth.get() {
+        0 => {}
+        1 => {
+            // The outer handler's frame and locals are gone, there is nothing left to return to
+            depth.set(2);
+            let context = fault_context(&frame);
+            crash::fatal(
+                &format_args!("nested page fault (#PF) accessing {:#x} at {}", address, Location(context.rip)),
+                &context,
+            );
+        }
+        // Faulted again while reporting the nested fault
+        _ => loop {
+            hlt();
+        },
+    }
+    depth.set(1);
+    let not_present = !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    if not_present && VirtAddr::try_new(address).is_ok_and(grow_stack) {
+        depth.set(0);
+        return;
+    }
+    let context = fault_context(&frame);
+    check_stack_overflow(address, &context);
+    crash::fatal(
+        &format_args!(
+            "page fault (#PF) accessing {:#x} at {}: {}",
+            address,
+            Location(context.rip),
+            PageFaultReason(error_code)
+        ),
+        &context,
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault(frame: InterruptStackFrame, error_code: u64) {
+    let context = fault_context(&frame);
+    crash::fatal(
+        &format_args!("general protection fault (#GP) at {}, {}", Location(context.rip), SelectorError(error_code)),
+        &context,
+    );
+}
+
+extern "x86-interrupt" fn stack_segment_fault(frame: InterruptStackFrame, error_code: u64) {
+    let context = fault_context(&frame);
+    crash::fatal(
+        &format_args!("stack segment fault (#SS) at {}, {}", Location(context.rip), SelectorError(error_code)),
+        &context,
+    );
+}
+
+extern "x86-interrupt" fn segment_not_present(frame: InterruptStackFrame, error_code: u64) {
+    let context = fault_context(&frame);
+    crash::fatal(
+        &format_args!("segment not present (#NP) at {}, {}", Location(context.rip), SelectorError(error_code)),
+        &context,
+    );
+}
+
+extern "x86-interrupt" fn invalid_tss(frame: InterruptStackFrame, error_code: u64) {
+    let context = fault_context(&frame);
+    crash::fatal(
+        &format_args!("invalid TSS (#TS) at {}, {}", Location(context.rip), SelectorError(error_code)),
+        &context,
+    );
+}
+
+extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, _error_code: u64) -> ! {
+    let context = fault_context(&frame);
+    // Overflowing a stack faults again while pushing the #PF frame, CR2 still holds the guard address
+    check_stack_overflow(Cr2::read_raw(), &context);
+    crash::fatal(&format_args!("double fault (#DF) at {}", Location(context.rip)), &context);
+}
+
+extern "x86-interrupt" fn machine_check(frame: InterruptStackFrame) -> ! {
+    let context = fault_context(&frame);
+    crash::fatal(&format_args!("machine check (#MC) at {}", Location(context.rip)), &context);
+}
+
+extern "x86-interrupt" fn debug(frame: InterruptStackFrame) {
+    LOGGER.log_unstamped(Level::Debug, format_args!("Debug trap (#DB) at {}", Location(frame.instruction_pointer.as_u64())));
+}
+
+extern "x86-interrupt" fn breakpoint(frame: InterruptStackFrame) {
+    log::info!("Breakpoint (#BP) at {}", Location(frame.instruction_pointer.as_u64()));
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt(frame: InterruptStackFrame) {
+    crash::halt_if_crashing();
+    LOGGER.log_unstamped(Level::Warn, format_args!("Non maskable interrupt at {}", Location(frame.instruction_pointer.as_u64())));
+}
+
+/// Nothing to handle and, unlike every other APIC interrupt, no EOI to send
+extern "x86-interrupt" fn spurious_interrupt(_frame: InterruptStackFrame) {}
+
+fn unexpected_interrupt(frame: InterruptStackFrame, index: u8, _error_code: Option<u64>) {
+    log::warn!("Unexpected interrupt {:#x} at {}", index, Location(frame.instruction_pointer.as_u64()));
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![
+        breakpoint_resumes,
+        decodes_selector_errors,
+        decodes_page_fault_errors,
+        formats_stack_overflow,
+    ];
+
+    fn breakpoint_resumes() {
+        x86_64::instructions::interrupts::int3();
+    }
+
+    fn decodes_selector_errors() {
+        use alloc::string::ToString;
+        assert_eq!(SelectorError(0).to_string(), "error code 0 (not selector related)");
+        assert_eq!(SelectorError(0x18).to_string(), "error code 0x18: GDT index 3");
+        assert_eq!(SelectorError(0x2b).to_string(), "error code 0x2b: IDT index 5, external event");
+    }
+
+    fn decodes_page_fault_errors() {
+        use alloc::string::ToString;
+        let code = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+        assert_eq!(PageFaultReason(code).to_string(), "supervisor write, protection violation (error code 0x3)");
+        let code = PageFaultErrorCode::INSTRUCTION_FETCH;
+        assert_eq!(PageFaultReason(code).to_string(), "supervisor instruction fetch, page not present (error code 0x10)");
+    }
+
+    fn formats_stack_overflow() {
+        use alloc::format;
+        let stack = Stack {
+            stack_ref: crate::memory::StackRef::new(7).unwrap(),
+            stack_base: VirtAddr::new(0x10_0000),
+            max_stack_size: 0x4000,
+        };
+        assert_eq!(
+            format!("{}", StackOverflow(stack)),
+            format!("stack overflow on stack #7 (core {}, size 0x4000)", core_index())
+        );
+    }
+}
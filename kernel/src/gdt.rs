@@ -0,0 +1,101 @@
+use core::ptr::addr_of;
+
+use x86_64::{
+    instructions::{
+        segmentation::{Segment, CS, DS, ES, SS},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable},
+        paging::PageSize,
+        tss::TaskStateSegment,
+    },
+};
+
+use crate::{percpu, stack::alloc_kernel_stack, MAX_PROC_COUNT, PAGE_SIZE};
+
+// Every core gets its own GDT and TSS. The TSS only carries the interrupt stack table: #DF, NMI, #MC
+// and #PF switch to a dedicated stack, so an overflowed kernel stack still leaves room to report it
+// and a growing one can be extended from the page fault handler.
+
+pub(crate) const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub(crate) const NMI_IST_INDEX: u16 = 1;
+pub(crate) const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub(crate) const PAGE_FAULT_IST_INDEX: u16 = 3;
+const IST_INDICES: [u16; 4] = [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX, PAGE_FAULT_IST_INDEX];
+const IST_STACK_SIZE: u64 = 4 * PAGE_SIZE::SIZE;
+
+struct CoreTables {
+    gdt: GlobalDescriptorTable,
+    tss: TaskStateSegment,
+}
+
+static mut CORE_TABLES: [CoreTables; MAX_PROC_COUNT] = [const {
+    CoreTables {
+        gdt: GlobalDescriptorTable::new(),
+        tss: TaskStateSegment::new(),
+    }
+}; MAX_PROC_COUNT];
+
+/// Allocates the IST stacks of the executing core and loads its own GDT and TSS, once per core
+pub(crate) fn init_core() {
+    let core = percpu::core_index();
+    assert!(core < MAX_PROC_COUNT, "core {} has no descriptor tables", core);
+    for index in IST_INDICES {
+        // Fully mapped, these are the stacks that handle faults on growing stacks
+        let stack = alloc_kernel_stack(IST_STACK_SIZE, IST_STACK_SIZE).expect("failed to allocate IST stack");
+        unsafe { CORE_TABLES[core].tss.interrupt_stack_table[index as usize] = stack.stack_base };
+    }
+
+    let tss: &'static TaskStateSegment = unsafe { &*addr_of!(CORE_TABLES[core].tss) };
+    let mut gdt = GlobalDescriptorTable::new();
+    let code = gdt.append(Descriptor::kernel_code_segment());
+    let data = gdt.append(Descriptor::kernel_data_segment());
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
+    unsafe { CORE_TABLES[core].gdt = gdt };
+    let gdt: &'static GlobalDescriptorTable = unsafe { &*addr_of!(CORE_TABLES[core].gdt) };
+    gdt.load();
+    unsafe {
+        CS::set_reg(code);
+        SS::set_reg(data);
+        DS::set_reg(data);
+        ES::set_reg(data);
+        load_tss(tss_selector);
+    }
+    percpu::current().set_tss(tss);
+    log::debug!("GDT and TSS loaded on core {}", core);
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use x86_64::{
+        structures::paging::{mapper::TranslateResult, Translate},
+        VirtAddr,
+    };
+
+    use super::*;
+    use crate::{
+        memory::{get_active_opt, UncladCustomPageFlags},
+        stack::STACK_REFS,
+        testing::test_cases,
+        PHYS_OFFSET,
+    };
+
+    test_cases![ist_stacks_are_registered];
+
+    fn ist_stacks_are_registered() {
+        let tss = unsafe { &*addr_of!(CORE_TABLES[percpu::core_index()].tss) };
+        let mapper = unsafe { get_active_opt(VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64)) };
+        for index in IST_INDICES {
+            let top = tss.interrupt_stack_table[index as usize];
+            assert!(!top.is_null(), "IST {} not set", index);
+            let stack_ref = match mapper.translate(top - 8u64) {
+                TranslateResult::Mapped { flags, .. } => flags.stack_ref().expect("IST page not marked as stack"),
+                _ => panic!("IST {} not mapped", index),
+            };
+            let stack = unsafe { STACK_REFS[stack_ref] };
+            assert_eq!(stack.stack_base, top);
+            assert_eq!(stack.max_stack_size as u64, IST_STACK_SIZE);
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use acpi::{platform::interrupt::Polarity, AcpiTables, HpetInfo};
+use conquer_once::spin::OnceCell;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    calibration::ExtendedCounter,
+    ioapic::{self, Trigger},
+    memory::phys_to_virt,
+    OffsetMappedHandler,
+};
+
+// High Precision Event Timer, found through the ACPI HPET table. The main counter runs at a fixed
+// frequency of at least 10 MHz and is readable from every core, which makes it the calibration
+// reference of choice. Its comparators fire one-shot or periodic interrupts through the IOAPIC
+// or, where supported, as MSIs straight to a local APIC.
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0F0;
+const REG_TIMER_CONFIG: usize = 0x100;
+const REG_TIMER_COMPARATOR: usize = 0x108;
+const REG_TIMER_FSB_ROUTE: usize = 0x110;
+const TIMER_STRIDE: usize = 0x20;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE: u64 = 0b11111 << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_FSB_CAPABLE: u64 = 1 << 15;
+const TIMER_ROUTE_CAPABILITIES_SHIFT: u64 = 32;
+
+/// The spec caps the counter period at 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+/// Where MSIs to a local APIC go, the destination APIC ID sits in bits 12..20
+const MSI_ADDRESS: u64 = 0xFEE0_0000;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+/// One bit per comparator handed out by `claim`
+static CLAIMED: AtomicU32 = AtomicU32::new(0);
+/// Extends a 32 bit main counter to 64 bits
+static COUNTER: ExtendedCounter = ExtendedCounter::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NotPresent,
+    /// Every comparator with the requested capabilities is claimed
+    NoComparator,
+    /// The comparator only does one-shot
+    NotPeriodic,
+    /// The comparator can't be wired that way, see `Comparator::ioapic_routes`
+    RouteUnsupported,
+}
+
+/// Where a comparator's interrupt goes. Either way it arrives as fixed `vector` on the core with
+/// xAPIC ID `apic_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// Edge triggered through the IOAPIC input `gsi`, one of the comparator's `ioapic_routes`
+    IoApic { gsi: u32, vector: u8, apic_id: u32 },
+    /// Front side bus delivery, an MSI written by the HPET itself
+    Fsb { vector: u8, apic_id: u32 },
+}
+
+struct Hpet {
+    base: VirtAddr,
+    period_fs: u64,
+    comparators: u8,
+    counter_64bit: bool,
+}
+
+// SAFETY: Only MMIO behind `base`, comparators are handed out to one owner each through `CLAIMED`
+unsafe impl Send for Hpet {}
+unsafe impl Sync for Hpet {}
+
+impl Hpet {
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { self.base.as_ptr::<u64>().byte_add(offset).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u64) {
+        unsafe { self.base.as_mut_ptr::<u64>().byte_add(offset).write_volatile(value) }
+    }
+}
+
+/// Maps the HPET from the ACPI table and starts its main counter. Leaves `is_present` false if
+/// there is none.
+pub(crate) fn init(tables: &AcpiTables<OffsetMappedHandler>) {
+    let Ok(info) = HpetInfo::new(tables) else {
+        log::info!("No HPET table, falling back to other clocks");
+        return;
+    };
+    let base = phys_to_virt(PhysAddr::new(info.base_address as u64));
+    let mut hpet = Hpet { base, period_fs: 0, comparators: info.num_comparators(), counter_64bit: info.main_counter_is_64bits() };
+    let capabilities = hpet.read(REG_CAPABILITIES);
+    hpet.period_fs = capabilities >> 32;
+    if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+        log::warn!("HPET reports a counter period of {} fs, ignoring it", hpet.period_fs);
+        return;
+    }
+    hpet.write(REG_CONFIG, 0);
+    hpet.write(REG_MAIN_COUNTER, 0);
+    for n in 0..hpet.comparators as usize {
+        let config = hpet.read(REG_TIMER_CONFIG + n * TIMER_STRIDE);
+        hpet.write(REG_TIMER_CONFIG + n * TIMER_STRIDE, config & !(TIMER_INTERRUPT_ENABLE | TIMER_FSB_ENABLE | TIMER_PERIODIC));
+    }
+    // Without legacy replacement, so the PIT and RTC keep their interrupts and the comparators go
+    // through the IOAPIC
+    hpet.write(REG_CONFIG, CONFIG_ENABLE);
+    log::info!(
+        "HPET at {:#x}: {} kHz, {} comparators, {} bit counter",
+        info.base_address,
+        FS_PER_NS * 1_000_000 / hpet.period_fs,
+        hpet.comparators,
+        if hpet.counter_64bit { 64 } else { 32 }
+    );
+    HPET.init_once(|| hpet);
+}
+
+pub fn is_present() -> bool {
+    HPET.get().is_some()
+}
+
+fn hpet() -> &'static Hpet {
+    HPET.get().expect("no HPET")
+}
+
+/// Main counter frequency in Hz
+pub fn frequency() -> u64 {
+    FS_PER_NS * 1_000_000_000 / hpet().period_fs
+}
+
+/// Femtoseconds per main counter tick
+pub fn period_fs() -> u64 {
+    hpet().period_fs
+}
+
+/// The main counter, never going backwards. A 32 bit counter is extended in software, which only
+/// holds as long as something reads it at least once per wrap, every few minutes.
+pub fn counter() -> u64 {
+    let hpet = hpet();
+    let raw = hpet.read(REG_MAIN_COUNTER);
+    if hpet.counter_64bit {
+        return raw;
+    }
+    COUNTER.extend(raw & 0xFFFF_FFFF, 32)
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * hpet().period_fs as u128 / FS_PER_NS as u128) as u64
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    (ns as u128 * FS_PER_NS as u128).div_ceil(hpet().period_fs as u128) as u64
+}
+
+/// Nanoseconds since `init`
+pub fn nanos() -> u64 {
+    ticks_to_ns(counter())
+}
+
+/// One of the HPET's comparators, stopped again when dropped
+pub struct Comparator {
+    index: usize,
+    /// IOAPIC input it's wired to, masked again on drop
+    gsi: Option<u32>,
+}
+
+impl Comparator {
+    /// Takes the first free comparator, one that can run periodically if `periodic` is set
+    pub fn claim(periodic: bool) -> Result<Self, HpetError> {
+        let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+        for index in 0..hpet.comparators as usize {
+            let config = hpet.read(REG_TIMER_CONFIG + index * TIMER_STRIDE);
+            if periodic && config & TIMER_PERIODIC_CAPABLE == 0 {
+                continue;
+            }
+            if CLAIMED.fetch_or(1 << index, Ordering::AcqRel) & (1 << index) == 0 {
+                return Ok(Self { index, gsi: None });
+            }
+        }
+        Err(HpetError::NoComparator)
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    fn config(&self) -> u64 {
+        hpet().read(REG_TIMER_CONFIG + self.index * TIMER_STRIDE)
+    }
+
+    fn set_config(&self, config: u64) {
+        hpet().write(REG_TIMER_CONFIG + self.index * TIMER_STRIDE, config);
+    }
+
+    fn set_comparator(&self, value: u64) {
+        hpet().write(REG_TIMER_COMPARATOR + self.index * TIMER_STRIDE, value);
+    }
+
+    /// Bit N set if IOAPIC input N can be the `Route::IoApic` GSI
+    pub fn ioapic_routes(&self) -> u32 {
+        (self.config() >> TIMER_ROUTE_CAPABILITIES_SHIFT) as u32
+    }
+
+    pub fn supports_fsb(&self) -> bool {
+        self.config() & TIMER_FSB_CAPABLE != 0
+    }
+
+    pub fn supports_periodic(&self) -> bool {
+        self.config() & TIMER_PERIODIC_CAPABLE != 0
+    }
+
+    /// Wires the interrupt up, the comparator only raises it once started
+    pub fn set_route(&mut self, route: Route) -> Result<(), HpetError> {
+        let mut config = self.config() & !(TIMER_ROUTE | TIMER_FSB_ENABLE | TIMER_LEVEL_TRIGGERED);
+        match route {
+            Route::IoApic { gsi, vector, apic_id } => {
+                if gsi >= 32 || self.ioapic_routes() & (1 << gsi) == 0 || !ioapic::has_gsi(gsi) {
+                    return Err(HpetError::RouteUnsupported);
+                }
+                ioapic::route(gsi, vector, apic_id, Trigger::Edge, Polarity::ActiveHigh);
+                config |= (gsi as u64) << TIMER_ROUTE_SHIFT;
+                if let Some(old) = self.gsi.replace(gsi).filter(|old| *old != gsi) {
+                    ioapic::mask(old);
+                }
+            }
+            Route::Fsb { vector, apic_id } => {
+                if !self.supports_fsb() || apic_id > 0xFF {
+                    return Err(HpetError::RouteUnsupported);
+                }
+                // Address in the upper half, data in the lower
+                let address = MSI_ADDRESS | (apic_id as u64) << 12;
+                hpet().write(REG_TIMER_FSB_ROUTE + self.index * TIMER_STRIDE, address << 32 | vector as u64);
+                config |= TIMER_FSB_ENABLE;
+                if let Some(gsi) = self.gsi.take() {
+                    ioapic::mask(gsi);
+                }
+            }
+        }
+        self.set_config(config);
+        Ok(())
+    }
+
+    /// Fires once, `ticks` main counter ticks from now, replacing whatever was running
+    pub fn start_one_shot(&mut self, ticks: u64) {
+        let config = self.config() & !TIMER_PERIODIC;
+        self.set_config(config & !TIMER_INTERRUPT_ENABLE);
+        self.set_comparator(self.deadline(ticks));
+        self.set_config(config | TIMER_INTERRUPT_ENABLE);
+    }
+
+    /// Fires every `ticks` main counter ticks, replacing whatever was running
+    pub fn start_periodic(&mut self, ticks: u64) -> Result<(), HpetError> {
+        if !self.supports_periodic() {
+            return Err(HpetError::NotPeriodic);
+        }
+        let config = self.config() & !TIMER_INTERRUPT_ENABLE;
+        self.set_config(config);
+        // With the value set bit the first write sets the next expiry, the second the period
+        self.set_config(config | TIMER_PERIODIC | TIMER_VALUE_SET);
+        self.set_comparator(self.deadline(ticks));
+        self.set_comparator(ticks);
+        self.set_config(config | TIMER_PERIODIC | TIMER_INTERRUPT_ENABLE);
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        self.set_config(self.config() & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+    }
+
+    /// Comparator value `ticks` from now, wrapped to 32 bit if the comparator is
+    fn deadline(&self, ticks: u64) -> u64 {
+        let deadline = hpet().read(REG_MAIN_COUNTER).wrapping_add(ticks);
+        let config = self.config();
+        if config & TIMER_64BIT_CAPABLE == 0 || config & TIMER_32BIT_MODE != 0 {
+            deadline & 0xFFFF_FFFF
+        } else {
+            deadline
+        }
+    }
+}
+
+impl Drop for Comparator {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(gsi) = self.gsi {
+            ioapic::mask(gsi);
+        }
+        CLAIMED.fetch_and(!(1 << self.index), Ordering::AcqRel);
+    }
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use apic::Apic;
+    use x86_64::structures::idt::InterruptStackFrame;
+
+    use super::*;
+    use crate::{percpu, testing::{self, test_cases}, tsc, IDT};
+
+    test_cases![
+        counter_runs_at_reported_frequency,
+        tick_conversions_round_trip,
+        comparators_are_claimed_once,
+        one_shot_fires_through_ioapic,
+        periodic_fires_through_fsb,
+    ];
+
+    const TEST_VECTOR: u8 = 0xE0;
+    const FIRE_TIMEOUT_US: u64 = 100_000;
+    static FIRES: AtomicU32 = AtomicU32::new(0);
+
+    extern "x86-interrupt" fn test_interrupt(_frame: InterruptStackFrame) {
+        FIRES.fetch_add(1, Ordering::SeqCst);
+        percpu::current().with_local_apic(|apic| apic.eoi());
+    }
+
+    fn install_test_handler() {
+        unsafe { IDT[TEST_VECTOR].set_handler_fn(test_interrupt) };
+        FIRES.store(0, Ordering::SeqCst);
+    }
+
+    fn counter_runs_at_reported_frequency() {
+        if !is_present() {
+            return testing::skip("no HPET");
+        }
+        assert!(frequency() >= 10_000_000, "HPET at {} Hz", frequency());
+        let (start, tsc_start) = (nanos(), tsc::read());
+        tsc::delay_us(2_000);
+        let (elapsed, tsc_elapsed) = (nanos() - start, tsc::read() - tsc_start);
+        let tsc_ns = tsc_elapsed * 1_000_000_000 / tsc::frequency();
+        assert!(testing::timing_matches(elapsed, tsc_ns), "HPET {} ns, TSC {} ns", elapsed, tsc_ns);
+    }
+
+    fn tick_conversions_round_trip() {
+        if !is_present() {
+            return testing::skip("no HPET");
+        }
+        assert_eq!(ns_to_ticks(0), 0);
+        // Rounded up, so a wait is never short
+        assert!(ticks_to_ns(ns_to_ticks(1_000)) >= 1_000);
+        assert_eq!(ticks_to_ns(frequency()) / 1_000_000, 1_000);
+    }
+
+    fn comparators_are_claimed_once() {
+        if !is_present() {
+            return testing::skip("no HPET");
+        }
+        let first = Comparator::claim(false).unwrap();
+        let second = Comparator::claim(false);
+        if let Ok(second) = &second {
+            assert_ne!(first.index(), second.index());
+        }
+        let index = first.index();
+        drop(first);
+        drop(second);
+        assert_eq!(Comparator::claim(false).unwrap().index(), index);
+    }
+
+    fn one_shot_fires_through_ioapic() {
+        if !is_present() {
+            return testing::skip("no HPET");
+        }
+        install_test_handler();
+        let mut comparator = Comparator::claim(false).unwrap();
+        // The highest allowed input, the low ones are the ISA lines
+        let routes = comparator.ioapic_routes();
+        let Some(gsi) = (0..32).rev().find(|gsi| routes & (1 << gsi) != 0 && ioapic::has_gsi(*gsi)) else {
+            return testing::skip("no IOAPIC input the comparator can use");
+        };
+        let apic_id = percpu::current().apic_id();
+        comparator.set_route(Route::IoApic { gsi, vector: TEST_VECTOR, apic_id }).unwrap();
+        comparator.start_one_shot(ns_to_ticks(1_000_000));
+        assert!(tsc::wait_until(FIRE_TIMEOUT_US, || FIRES.load(Ordering::SeqCst) > 0), "one-shot never fired");
+        tsc::delay_us(5_000);
+        assert_eq!(FIRES.load(Ordering::SeqCst), 1);
+    }
+
+    fn periodic_fires_through_fsb() {
+        if !is_present() {
+            return testing::skip("no HPET");
+        }
+        install_test_handler();
+        let Ok(mut comparator) = Comparator::claim(true) else {
+            return testing::skip("no periodic comparator");
+        };
+        let apic_id = percpu::current().apic_id();
+        if comparator.set_route(Route::Fsb { vector: TEST_VECTOR, apic_id }) == Err(HpetError::RouteUnsupported) {
+            // QEMU only has FSB delivery with `-global hpet.msi=on`
+            return testing::skip("no FSB delivery");
+        }
+        comparator.start_periodic(ns_to_ticks(1_000_000)).unwrap();
+        let fired = tsc::wait_until(FIRE_TIMEOUT_US, || FIRES.load(Ordering::SeqCst) >= 3);
+        drop(comparator);
+        assert!(fired, "periodic comparator fired {} times", FIRES.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,155 @@
+use acpi::platform::interrupt::{InterruptModel, Polarity};
+use alloc::{alloc::Global, vec::Vec};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::phys_to_virt;
+
+// IOAPICs from the MADT. Only what routing a global system interrupt (GSI) to a vector on one core
+// needs, the ISA lines stay on the 8259s until everything using them moved over.
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DESTINATION_SHIFT: u64 = 56;
+
+static IO_APICS: OnceCell<Vec<IoApic>> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Register select and window are a pair, the lock keeps another core from moving the select
+/// in between
+struct IoApic {
+    window: Mutex<VirtAddr>,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        let base = self.window.lock();
+        unsafe {
+            base.as_mut_ptr::<u32>().byte_add(IOREGSEL).write_volatile(register);
+            base.as_ptr::<u32>().byte_add(IOWIN).read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        let base = self.window.lock();
+        unsafe {
+            base.as_mut_ptr::<u32>().byte_add(IOREGSEL).write_volatile(register);
+            base.as_mut_ptr::<u32>().byte_add(IOWIN).write_volatile(value);
+        }
+    }
+
+    fn write_redirection(&self, pin: u32, entry: u64) {
+        let register = REG_REDIRECTION + pin * 2;
+        // Masked while half written
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    #[cfg(feature = "kernel-tests")]
+    fn read_redirection(&self, pin: u32) -> u64 {
+        let register = REG_REDIRECTION + pin * 2;
+        (self.read(register + 1) as u64) << 32 | self.read(register) as u64
+    }
+
+    fn pin(&self, gsi: u32) -> Option<u32> {
+        gsi.checked_sub(self.gsi_base).filter(|pin| *pin < self.entries)
+    }
+}
+
+/// Maps every IOAPIC in the MADT and masks all their inputs
+pub(crate) fn init(model: &InterruptModel<Global>) {
+    let InterruptModel::Apic(apic) = model else {
+        log::warn!("No APIC interrupt model in the MADT, IOAPIC routing unavailable");
+        return;
+    };
+    let io_apics = apic
+        .io_apics
+        .iter()
+        .map(|io_apic| {
+            let window = phys_to_virt(PhysAddr::new(io_apic.address as u64));
+            let mut io_apic = IoApic { window: Mutex::new(window), gsi_base: io_apic.global_system_interrupt_base, entries: 0 };
+            io_apic.entries = ((io_apic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+            for pin in 0..io_apic.entries {
+                io_apic.write_redirection(pin, REDIRECTION_MASKED);
+            }
+            log::debug!("IOAPIC at {:#x}: GSIs {}..{}", *io_apic.window.lock(), io_apic.gsi_base, io_apic.gsi_base + io_apic.entries);
+            io_apic
+        })
+        .collect();
+    IO_APICS.init_once(|| io_apics);
+}
+
+fn with_pin<R>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> R) -> Option<R> {
+    let (io_apic, pin) = IO_APICS.get()?.iter().find_map(|io_apic| Some((io_apic, io_apic.pin(gsi)?)))?;
+    Some(f(io_apic, pin))
+}
+
+/// Whether some IOAPIC has an input for `gsi`
+pub fn has_gsi(gsi: u32) -> bool {
+    with_pin(gsi, |_, _| ()).is_some()
+}
+
+/// Delivers `gsi` as fixed `vector` to the core with xAPIC ID `apic_id` and unmasks it. Returns
+/// false if no IOAPIC has that input.
+pub fn route(gsi: u32, vector: u8, apic_id: u32, trigger: Trigger, polarity: Polarity) -> bool {
+    assert!(apic_id <= 0xFF, "IOAPIC destinations are 8 bit, APIC ID {} needs interrupt remapping", apic_id);
+    let mut entry = vector as u64 | (apic_id as u64) << REDIRECTION_DESTINATION_SHIFT;
+    if trigger == Trigger::Level {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    with_pin(gsi, |io_apic, pin| io_apic.write_redirection(pin, entry)).is_some()
+}
+
+pub fn mask(gsi: u32) {
+    with_pin(gsi, |io_apic, pin| io_apic.write_redirection(pin, REDIRECTION_MASKED));
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![io_apics_cover_gsi_zero, route_and_mask_round_trip];
+
+    fn io_apics_cover_gsi_zero() {
+        let io_apics = IO_APICS.get().expect("no IOAPIC found");
+        assert!(!io_apics.is_empty());
+        assert!(has_gsi(0));
+        // The 82093AA has 24 inputs, nothing has fewer than the 16 ISA lines
+        assert!(io_apics.iter().all(|io_apic| io_apic.entries >= 16));
+    }
+
+    fn route_and_mask_round_trip() {
+        // The last input of the first IOAPIC, the ISA lines below are in use
+        let io_apic = &IO_APICS.get().unwrap()[0];
+        let gsi = io_apic.gsi_base + io_apic.entries - 1;
+        // Edge triggered and active high, an idle input never fires
+        assert!(route(gsi, 0xE0, 1, Trigger::Edge, Polarity::ActiveHigh));
+        let entry = with_pin(gsi, |io_apic, pin| io_apic.read_redirection(pin)).unwrap();
+        assert_eq!(entry & 0xFF, 0xE0);
+        assert_eq!(entry >> REDIRECTION_DESTINATION_SHIFT, 1);
+        assert_eq!(entry & (REDIRECTION_LEVEL_TRIGGERED | REDIRECTION_ACTIVE_LOW | REDIRECTION_MASKED), 0);
+        mask(gsi);
+        let entry = with_pin(gsi, |io_apic, pin| io_apic.read_redirection(pin)).unwrap();
+        assert_ne!(entry & REDIRECTION_MASKED, 0);
+        assert!(!route(u32::MAX, 0xE0, 0, Trigger::Edge, Polarity::ActiveHigh));
+    }
+}
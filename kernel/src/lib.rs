@@ -0,0 +1,238 @@
+#![no_std]
+#![feature(abi_x86_interrupt)]
+#![allow(static_mut_refs)]
+#![feature(allocator_api)]
+#![feature(naked_functions)]
+#![feature(core_intrinsics)]
+#![feature(const_trait_impl)]
+#![feature(used_with_arg)]
+
+extern crate alloc;
+extern crate bootloader_api;
+
+use acpi::{AcpiHandler, AcpiTables, PhysicalMapping, PlatformInfo};
+use alloc::alloc::Global;
+use bootloader_api::{config::Mapping, info::FrameBufferInfo};
+use buddy_system_allocator::{LockedFrameAllocator, LockedHeap};
+use conquer_once::spin::OnceCell;
+use memory::{allocate_heap, assign_frames};
+use multicore::copy_ap_trampoline;
+use core::{cell::UnsafeCell, panic::PanicInfo, ptr::NonNull};
+use x86_64::{
+    instructions::interrupts, registers::{
+        control::{Cr0Flags, Cr4Flags},
+        segmentation::{Segment, CS},
+    }, structures::{
+        idt::InterruptDescriptorTable,
+        paging::{PageSize, PhysFrame, Size2MiB, Size4KiB},
+    }, PrivilegeLevel, VirtAddr
+};
+
+mod calibration;
+pub mod crash;
+mod exceptions;
+mod gdt;
+pub mod hpet;
+mod ioapic;
+pub mod logger;
+mod memory;
+mod x86_ext;
+pub mod multicore;
+pub mod percpu;
+mod pic;
+mod pit;
+pub mod pm_timer;
+pub mod qemu;
+pub mod rtc;
+pub mod serial;
+mod stack;
+pub mod symbols;
+#[cfg(feature = "kernel-tests")]
+pub mod testing;
+pub mod time;
+pub mod timers;
+pub mod trace;
+pub mod tsc;
+
+const ALLOC_ORDER: usize = 32;
+
+#[global_allocator]
+static HEAP: LockedHeap<ALLOC_ORDER> = LockedHeap::empty();
+
+pub(crate) const MAX_PROC_COUNT: usize = 32;
+pub(crate) const MAX_STACK_SIZE: usize = 0x8000;
+/// One entry per `StackRef` value
+pub(crate) const MAX_STACK_COUNT: usize = 1024;
+
+static mut FRAME_ALLOC: OnceCell<LockedFrameAllocator<ALLOC_ORDER>> = OnceCell::uninit();
+
+// ...
+pub(crate) static PHYS_OFFSET: OnceCell<usize> = OnceCell::uninit();
+pub(crate) static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
+pub(crate) type PAGE_SIZE = Size4KiB;
+pub(crate) fn init_logger(buffer: &'static mut [u8], info: FrameBufferInfo) {
+    logger::init(buffer, info);
+}
+pub(crate) fn init_frame_alloc() {
+    unsafe { FRAME_ALLOC.init_once(|| LockedFrameAllocator::new()) };
+}
+
+pub const CONFIG: bootloader_api::BootloaderConfig = {
+    let mut config = bootloader_api::BootloaderConfig::new_default();
+    config.kernel_stack_size = 100 * 1024; // 100 KiB
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+/// Brings up logging, memory and interrupts on the BSP, shared by every kernel entry point
+pub fn init(boot_info: &'static mut bootloader_api::BootInfo) -> AcpiTables<OffsetMappedHandler> {
+    let physical_offset = boot_info.physical_memory_offset.into_option().unwrap();
+    PHYS_OFFSET.init_once(|| physical_offset as usize);
+    crash::KERNEL_IMAGE_OFFSET.init_once(|| boot_info.kernel_image_offset);
+    let frame_buffer = boot_info.framebuffer.as_mut().unwrap();
+    let frame_buffer_info = frame_buffer.info().clone();
+    let raw_frame_buffer = frame_buffer.buffer_mut();
+    raw_frame_buffer.iter_mut().for_each(|byte| *byte = 0);
+    init_logger(raw_frame_buffer, frame_buffer_info);
+    log::info!("Logger initialized");
+    percpu::init_cpu(0, multicore::initial_apic_id(), None);
+    percpu::load(0);
+    init_frame_alloc();
+    log::info!("Frame allocator initialized");
+    let mut frame_alloc = unsafe { FRAME_ALLOC.get().unwrap().lock() };
+    log::trace!("Frame allocator locked");
+    match memory::find_low_frame(&boot_info.memory_regions) {
+        Some(frame) => {
+            memory::TRAMPOLINE_FRAME.init_once(|| frame);
+            log::debug!("Reserved {:#x} for the AP trampoline", frame.start_address());
+        }
+        None => log::warn!("No usable frame below 1 MiB, APs can't be started"),
+    }
+    match memory::find_frames_below_4g(&boot_info.memory_regions, multicore::AP_TABLE_FRAME_COUNT) {
+        Some(frames) => {
+            memory::AP_TABLE_FRAMES.init_once(|| frames);
+            log::debug!("Reserved {:?} for the AP page tables", frames);
+        }
+        None => log::warn!("No usable frames below 4 GiB for the AP page tables, APs can't be started"),
+    }
+    // Both ascending, the trampoline is below 1 MiB and the page tables above
+    let reserved = [
+        memory::TRAMPOLINE_FRAME.get().map(|frame| PhysFrame::range(*frame, *frame + 1)),
+        memory::AP_TABLE_FRAMES.get().copied(),
+    ];
+    assign_frames::<PAGE_SIZE, 32>(&boot_info.memory_regions, &mut frame_alloc, reserved.into_iter().flatten());
+    log::debug!("Frames assigned");
+    allocate_heap::<PAGE_SIZE, 32>(&mut frame_alloc);
+    // Everything from here on, the per-core stacks first, takes the lock itself
+    drop(frame_alloc);
+    log::info!("Heap allocated");
+    log_cpu_mode();
+    stack::init_stack_region();
+    gdt::init_core();
+    exceptions::init_idt();
+    serial::init_interrupts();
+    interrupts::enable();
+    log::debug!("Serial switched to interrupt driven I/O");
+    let mut physical_map = unsafe {
+        memory::get_active_opt(VirtAddr::new(
+            boot_info.physical_memory_offset.into_option().unwrap(),
+        ))
+    };
+    assert_cpu_state(
+        PrivilegeLevel::Ring0,
+        Cr4Flags::PHYSICAL_ADDRESS_EXTENSION,
+        Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::PAGING,
+    );
+    let tables = parse_acpi(
+        physical_map.phys_offset(),
+        boot_info.rsdp_addr.into_option().unwrap(),
+    );
+    match tables.platform_info() {
+        Ok(platform_info) => {
+            ioapic::init(&platform_info.interrupt_model);
+            pm_timer::init(platform_info.pm_timer.as_ref());
+        }
+        Err(err) => log::warn!("No platform info in the ACPI tables: {:?}", err),
+    }
+    hpet::init(&tables);
+    // After the HPET and PM timer, both are better calibration references than the PIT
+    tsc::calibrate();
+    time::init();
+    rtc::init(&tables);
+    time::init_wall_clock();
+    multicore::init_local_apic();
+    timers::init();
+    tables
+}
+
+#[derive(Clone)]
+pub struct OffsetMappedHandler {
+    pub offset: VirtAddr,
+}
+
+impl AcpiHandler for OffsetMappedHandler {
+    // TODO FIXME: This inline(never) annotation is required. Without it,
+    // LLVM replaces the `search_for_on_bios` call below with a `ud2`
+    // instruction. See https://github.com/rust-osdev/bootloader/issues/425
+    #[inline(never)]
+    unsafe fn map_physical_region<T>(
+        &self,
+        physical_address: usize,
+        size: usize,
+    ) -> PhysicalMapping<Self, T> {
+        unsafe {
+            PhysicalMapping::new(
+                physical_address,
+                NonNull::new((self.offset + physical_address as u64).as_mut_ptr()).unwrap(),
+                size,
+                size,
+                self.clone(),
+            )
+        }
+    }
+
+    fn unmap_physical_region<T>(_: &PhysicalMapping<Self, T>) {}
+}
+
+fn parse_acpi(offset: VirtAddr, rsdp_addr: u64) -> AcpiTables<OffsetMappedHandler> {
+    unsafe {
+        AcpiTables::from_rsdp(OffsetMappedHandler { offset }, rsdp_addr as usize).unwrap()
+    }
+}
+
+fn log_cpu_mode() {
+    let cr0 = x86_64::registers::control::Cr0::read();
+    let cr4 = x86_64::registers::control::Cr4::read();
+    let protected = cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE);
+    let paging = cr0.contains(Cr0Flags::PAGING);
+    let long_mode = cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION);
+    let cs = CS::get_reg();
+    let ring_level = cs.rpl();
+    log::trace!(
+        "Protected: {}; Paging: {}, Long mode: {}, Ring Level: {:?}",
+        protected,
+        paging,
+        long_mode,
+        ring_level
+    );
+}
+
+fn assert_cpu_state(privilege_level: PrivilegeLevel, cr4_flags: Cr4Flags, cr0_flags: Cr0Flags) {
+    let cs = CS::get_reg();
+    let ring_level = cs.rpl();
+    assert_eq!(ring_level, privilege_level, "Ring level mismatch");
+    let cr4 = x86_64::registers::control::Cr4::read();
+    assert!(cr4.contains(cr4_flags), "CR4 flag mismatch");
+    let cr0 = x86_64::registers::control::Cr0::read();
+    assert!(cr0.contains(cr0_flags), "CR0 flag mismatch");
+}
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    let context = crash::CrashContext {
+        rip: x86_64::registers::read_rip().as_u64(),
+        registers: crash::GeneralRegisters::capture(),
+        register_source: crash::RegisterSource::PanicHandler,
+    };
+    crash::fatal(info, &context)
+}
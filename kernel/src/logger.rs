@@ -0,0 +1,130 @@
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bootloader_api::info::FrameBufferInfo;
+use bootloader_x86_64_common::logger::LockedLogger;
+use conquer_once::spin::OnceCell;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use x86_64::instructions::interrupts;
+
+use crate::{
+    serial::{SerialConfig, COM1},
+    time::SystemTime,
+};
+
+// Fans every record out to COM1 and the framebuffer, each with its own level
+
+pub static LOGGER: KernelLogger = KernelLogger::new();
+
+pub struct KernelLogger {
+    framebuffer: OnceCell<LockedLogger>,
+    serial_level: AtomicUsize,
+    framebuffer_level: AtomicUsize,
+}
+
+const fn level_from_usize(level: usize) -> LevelFilter {
+    match level {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+impl KernelLogger {
+    const fn new() -> Self {
+        Self {
+            framebuffer: OnceCell::uninit(),
+            serial_level: AtomicUsize::new(LevelFilter::Trace as usize),
+            framebuffer_level: AtomicUsize::new(LevelFilter::Info as usize),
+        }
+    }
+
+    pub fn serial_level(&self) -> LevelFilter {
+        level_from_usize(self.serial_level.load(Ordering::Relaxed))
+    }
+
+    pub fn framebuffer_level(&self) -> LevelFilter {
+        level_from_usize(self.framebuffer_level.load(Ordering::Relaxed))
+    }
+
+    pub fn set_serial_level(&self, level: LevelFilter) {
+        self.serial_level.store(level as usize, Ordering::Relaxed);
+        self.update_max_level();
+    }
+
+    pub fn set_framebuffer_level(&self, level: LevelFilter) {
+        self.framebuffer_level.store(level as usize, Ordering::Relaxed);
+        self.update_max_level();
+    }
+
+    fn update_max_level(&self) {
+        log::set_max_level(self.serial_level().max(self.framebuffer_level()));
+    }
+
+    /// Writes to COM1 only and without the timestamp, for the NMI and #DB handlers. They can land
+    /// anywhere, in the middle of reading the clock or holding COM1 included, so the message is
+    /// dropped rather than waiting on a lock the interrupted code will never release.
+    pub fn log_unstamped(&self, level: Level, args: fmt::Arguments) {
+        if level > self.serial_level() {
+            return;
+        }
+        if let Some(mut port) = COM1.try_lock() {
+            let _ = write!(port, "{:5}: {}\r\n", level, args);
+        }
+    }
+
+    /// Releases the sink locks so a panic on a core that held them can still be reported
+    ///
+    /// # Safety
+    /// No other core may write through the sinks any more, the caller takes over the output
+    pub unsafe fn force_unlock(&self) {
+        unsafe { COM1.force_unlock() };
+        if let Some(framebuffer) = self.framebuffer.get() {
+            unsafe { framebuffer.force_unlock() };
+        }
+    }
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.serial_level() || metadata.level() <= self.framebuffer_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= self.serial_level() {
+            interrupts::without_interrupts(|| {
+                let mut port = COM1.lock();
+                let _ = match SystemTime::try_now() {
+                    Some(now) => write!(port, "{} {:5}: {}\r\n", now, record.level(), record.args()),
+                    None => write!(port, "{:5}: {}\r\n", record.level(), record.args()),
+                };
+            });
+        }
+        if record.level() <= self.framebuffer_level()
+            && let Some(framebuffer) = self.framebuffer.get()
+        {
+            framebuffer.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        interrupts::without_interrupts(|| COM1.lock().flush());
+    }
+}
+
+pub fn init(buffer: &'static mut [u8], info: FrameBufferInfo) {
+    // The bootloader's serial half is left off, COM1 belongs to our driver
+    LOGGER
+        .framebuffer
+        .init_once(move || LockedLogger::new(buffer, info, true, false));
+    if COM1.lock().init(SerialConfig::new()).is_err() {
+        LOGGER.serial_level.store(LevelFilter::Off as usize, Ordering::Relaxed);
+    }
+    log::set_logger(&LOGGER).expect("Logger already set");
+    LOGGER.update_max_level();
+}
@@ -1,15 +1,27 @@
 use core::{error::Error, ops::Range};
-use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use buddy_system_allocator::FrameAllocator;
+use conquer_once::spin::OnceCell;
 use x86_64::{
-    PhysAddr, VirtAddr,
+    PhysAddr, VirtAddr, align_up,
     structures::paging::{
         Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
-        PhysFrame, Size4KiB, frame, FrameAllocator as FrameAllocatorTrait,
+        PhysFrame, Size4KiB, frame::PhysFrameRange, FrameAllocator as FrameAllocatorTrait, page_table::PageTableEntry,
     },
 };
 
-use crate::{ALLOC_ORDER, HEAP, PHYS_OFFSET};
+use crate::{ALLOC_ORDER, FRAME_ALLOC, HEAP, PHYS_OFFSET, x86_ext::ToFrameNumeric};
+
+const LOW_MEMORY_END: u64 = 0x10_0000;
+/// First address a 32 bit pointer can't reach
+const ADDRESS_32BIT_END: u64 = 0x1_0000_0000;
+/// Frames `assign_frames` gives the heap so it works before `allocate_heap`
+const HEAP_PREALLOC_FRAMES: u64 = 4;
+
+/// Frame below 1 MiB set aside for the AP trampoline, see `find_low_frame`
+pub(crate) static TRAMPOLINE_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+/// Frames below 4 GiB set aside for the APs' page tables, see `find_frames_below_4g`
+pub(crate) static AP_TABLE_FRAMES: OnceCell<PhysFrameRange> = OnceCell::uninit();
 
 pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
@@ -28,6 +40,22 @@ pub unsafe fn get_active_opt(physical_memory_offset: VirtAddr) -> OffsetPageTabl
     OffsetPageTable::new(l4_table, physical_memory_offset)
 }
 
+/// Level 1 entry for `addr` in the active tables, `None` if a parent table is missing or the
+/// address is covered by a huge page. Unlike `Translate`, this also reaches non-present entries.
+pub(crate) unsafe fn leaf_entry(physical_memory_offset: VirtAddr, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let mut table = unsafe { active_level_4_table(physical_memory_offset) };
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        let next = physical_memory_offset + entry.addr().as_u64();
+        table = unsafe { &mut *next.as_mut_ptr::<PageTable>() };
+    }
+    Some(&mut table[page.p1_index()])
+}
+
 //TODO: Make const at somepoint once we can statically know offset
 pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
     let offset = unsafe{ *PHYS_OFFSET.get_unchecked() };
@@ -57,37 +85,97 @@ pub fn allocate_heap<S: PageSize, const O: usize>(
     unsafe { heap_lock.add_to_heap(PHYS_OFFSET.get().unwrap() + start_addr,PHYS_OFFSET.get().unwrap() + start_addr + HEAP_SIZE as usize)} ;
 }
 
-pub fn assign_frames<S: PageSize, const O: usize>(mr: &MemoryRegions, frame_allocator: &mut FrameAllocator<O>) {
+/// First usable, page aligned frame below 1 MiB, skipping frame 0 with the real mode IVT and BDA.
+/// Must be picked before `assign_frames` hands the memory map to the frame allocator.
+pub fn find_low_frame(mr: &MemoryRegions) -> Option<PhysFrame> {
+    mr.iter()
+        .filter(|r| r.kind == MemoryRegionKind::Usable)
+        .find_map(|r| {
+            let start = align_up(r.start.max(Size4KiB::SIZE), Size4KiB::SIZE);
+            let end = r.end.min(LOW_MEMORY_END);
+            (start + Size4KiB::SIZE <= end).then(|| PhysFrame::containing_address(PhysAddr::new(start)))
+        })
+}
+
+/// First `count` consecutive usable frames between 1 MiB and 4 GiB, for what has to be reachable
+/// through a 32 bit address. Must be picked before `assign_frames` too.
+pub fn find_frames_below_4g(mr: &MemoryRegions, count: u64) -> Option<PhysFrameRange> {
+    mr.iter()
+        .filter(|r| r.kind == MemoryRegionKind::Usable)
+        .find_map(|r| {
+            let start = align_up(r.start.max(LOW_MEMORY_END), Size4KiB::SIZE);
+            let end = r.end.min(ADDRESS_32BIT_END);
+            (start + count * Size4KiB::SIZE <= end).then(|| {
+                let first = PhysFrame::containing_address(PhysAddr::new(start));
+                PhysFrame::range(first, first + count)
+            })
+        })
+}
+
+/// Hands a frame that was kept out of `assign_frames` over to the frame allocator
+pub(crate) fn release_frame(frame: PhysFrame) {
+    let num = frame.to_frame_numeric();
+    unsafe { FRAME_ALLOC.get().unwrap().lock() }.add_frame(num, num + 1);
+}
+
+/// Gives every usable frame except the `reserved` ones, in ascending order, to the frame allocator
+pub fn assign_frames<S: PageSize, const O: usize>(
+    mr: &MemoryRegions,
+    frame_allocator: &mut FrameAllocator<O>,
+    reserved: impl Iterator<Item = PhysFrameRange<S>> + Clone,
+) {
     let regions = mr.iter();
     let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-    // map each region to its address range
-    let mut usable_frame_ranges = usable_regions.map(|r| {
-        PhysFrame::<S>::range_inclusive(
+    // map each region to its frame number range
+    let usable_frame_ranges = usable_regions.map(|r| {
+        let range = PhysFrame::<S>::range_inclusive(
             PhysFrame::containing_address(PhysAddr::new(r.start)),
             PhysFrame::containing_address(PhysAddr::new(r.end)),
-        )
+        );
+        (range.start.start_address().as_u64() / S::SIZE, range.end.start_address().as_u64() / S::SIZE)
     });
+    let frame_ranges = usable_frame_ranges.flat_map(|(start, end)| cut_reserved(start, end, reserved.clone()));
     log::info!("Assigning frames");
     let mut heap_allocated = false;
-    for frame_range in usable_frame_ranges.filter(|r| r.start.start_address().as_u64() != 0x8000) {
-        let mut frame_start = frame_range.start.start_address().as_u64() / S::SIZE;
-        let frame_end = frame_range.end.start_address().as_u64() / S::SIZE;
-        if !heap_allocated {
+    for (mut frame_start, frame_end) in frame_ranges {
+        if !heap_allocated && frame_end - frame_start > HEAP_PREALLOC_FRAMES {
                 let start_addr = frame_start * S::SIZE;
                 let mut guard = HEAP.lock();
-                unsafe { guard.init(PHYS_OFFSET.get().unwrap() + start_addr as usize, 4 * S::SIZE as usize)};
+                unsafe { guard.init(PHYS_OFFSET.get().unwrap() + start_addr as usize, (HEAP_PREALLOC_FRAMES * S::SIZE) as usize)};
                 drop(guard);
-                log::debug!("Heap pre-allocated with 1 frame");
+                log::debug!("Heap pre-allocated with {} frames", HEAP_PREALLOC_FRAMES);
                 heap_allocated = true;
-                frame_start += 1;
-        }
-        if frame_start >= frame_end {
-            continue;
+                frame_start += HEAP_PREALLOC_FRAMES;
         }
         frame_allocator.add_frame(frame_start as usize, frame_end as usize);
     }
 }
 
+/// The frame numbers `start..end` with the `reserved` ranges, in ascending order, cut out
+fn cut_reserved<S: PageSize>(
+    start: u64,
+    end: u64,
+    reserved: impl Iterator<Item = PhysFrameRange<S>>,
+) -> impl Iterator<Item = (u64, u64)> {
+    let number = |frame: PhysFrame<S>| frame.start_address().as_u64() / S::SIZE;
+    let mut reserved = reserved.map(move |range| (number(range.start), number(range.end)));
+    let mut next = start;
+    core::iter::from_fn(move || {
+        while next < end {
+            let (piece_end, resume) = match reserved.next() {
+                Some((cut_start, cut_end)) => (cut_start.min(end), next.max(cut_end)),
+                None => (end, end),
+            };
+            let piece = (next, piece_end);
+            next = resume;
+            if piece.0 < piece.1 {
+                return Some(piece);
+            }
+        }
+        None
+    })
+}
+
 
 // At most 10 bits can be set in the flags field, so we must guarantee this
 #[derive(Debug, Clone, Copy)]
@@ -129,6 +217,7 @@ pub(crate) trait UncladCustomPageFlags {
     fn is_guard(&self) -> bool;
     #[must_use]
     fn assign_stack_ref(&self, stack_ref: StackRef) -> Self;
+    fn stack_ref(&self) -> Option<StackRef>;
 }
 
 impl const UncladCustomPageFlags for PageTableFlags {
@@ -153,4 +242,129 @@ impl const UncladCustomPageFlags for PageTableFlags {
         let val = (stack_ref.as_u16() as u64) << 52;
         Self::union(*self,PageTableFlags::from_bits(val).unwrap())
     }
+
+    fn stack_ref(&self) -> Option<StackRef> {
+        if !self.is_stack() {
+            return None;
+        }
+        StackRef::new(((self.bits() >> 52) & ((1 << StackRef::MAX_BITS) - 1)) as u16)
+    }
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use alloc::{boxed::Box, vec, vec::Vec};
+
+    use bootloader_api::info::MemoryRegion;
+
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![
+        stack_ref_rejects_more_than_ten_bits,
+        stack_ref_try_from,
+        stack_and_guard_bits_are_independent,
+        stack_ref_lands_in_available_high_bits,
+        stack_ref_reads_back,
+        phys_to_virt_applies_offset,
+        low_frame_is_usable_and_below_one_mib,
+        frames_below_4g_skip_low_memory,
+        reserved_frames_are_cut_out,
+    ];
+
+    fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+        let mut region = MemoryRegion::empty();
+        region.start = start;
+        region.end = end;
+        region.kind = kind;
+        region
+    }
+
+    fn regions(list: Vec<MemoryRegion>) -> MemoryRegions {
+        MemoryRegions::from(Box::leak(list.into_boxed_slice()))
+    }
+
+    fn stack_ref_rejects_more_than_ten_bits() {
+        assert_eq!(StackRef::new(0x3FF).unwrap().as_u16(), 0x3FF);
+        assert!(StackRef::new(0x400).is_none());
+        assert!(StackRef::new(u16::MAX).is_none());
+    }
+
+    fn stack_ref_try_from() {
+        assert!(StackRef::try_from(12u16).is_ok());
+        assert!(StackRef::try_from(1u16 << 10).is_err());
+    }
+
+    fn stack_and_guard_bits_are_independent() {
+        let flags = PageTableFlags::PRESENT.mark_as_stack();
+        assert!(flags.is_stack());
+        assert!(!flags.is_guard());
+        let guard = PageTableFlags::empty().mark_as_guard();
+        assert!(guard.is_guard());
+        assert!(!guard.is_stack());
+        assert!(!guard.contains(PageTableFlags::PRESENT));
+    }
+
+    fn stack_ref_lands_in_available_high_bits() {
+        let stack_ref = StackRef::new(0x2A5).unwrap();
+        let flags = PageTableFlags::WRITABLE.assign_stack_ref(stack_ref);
+        assert_eq!((flags.bits() >> 52) & 0x3FF, 0x2A5);
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+        // Bit 63 is NO_EXECUTE and must never be touched by a stack ref
+        assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+    }
+
+    fn stack_ref_reads_back() {
+        let stack_ref = StackRef::new(0x155).unwrap();
+        let flags = PageTableFlags::PRESENT.mark_as_stack().assign_stack_ref(stack_ref);
+        assert_eq!(flags.stack_ref().unwrap().as_u16(), 0x155);
+        assert!(PageTableFlags::PRESENT.assign_stack_ref(stack_ref).stack_ref().is_none());
+    }
+
+    fn phys_to_virt_applies_offset() {
+        let offset = *PHYS_OFFSET.get().unwrap() as u64;
+        assert_eq!(phys_to_virt(PhysAddr::new(0x1000)).as_u64(), offset + 0x1000);
+    }
+
+    fn low_frame_is_usable_and_below_one_mib() {
+        let map = regions(vec![
+            region(0, 0x1000, MemoryRegionKind::Usable),
+            region(0x1000, 0x8000, MemoryRegionKind::Bootloader),
+            region(0x8800, 0x9_f000, MemoryRegionKind::Usable),
+        ]);
+        assert_eq!(find_low_frame(&map).unwrap().start_address().as_u64(), 0x9000);
+
+        let map = regions(vec![
+            region(0x500, 0x1800, MemoryRegionKind::Usable),
+            region(0x10_0000, 0x800_0000, MemoryRegionKind::Usable),
+        ]);
+        assert!(find_low_frame(&map).is_none());
+    }
+
+    fn frames_below_4g_skip_low_memory() {
+        let map = regions(vec![
+            region(0x1000, 0x9_f000, MemoryRegionKind::Usable),
+            region(0x10_0000, 0x10_3000, MemoryRegionKind::Usable),
+            region(0x20_0800, 0x30_0000, MemoryRegionKind::Usable),
+        ]);
+        let frames = find_frames_below_4g(&map, 4).unwrap();
+        assert_eq!(frames.start.start_address().as_u64(), 0x20_1000);
+        assert_eq!(frames.len(), 4);
+
+        let map = regions(vec![region(0xFFFF_E000, 0x1_0001_0000, MemoryRegionKind::Usable)]);
+        assert!(find_frames_below_4g(&map, 4).is_none());
+    }
+
+    fn reserved_frames_are_cut_out() {
+        let frames = |start: u64, end: u64| {
+            let frame = |number: u64| PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(number * Size4KiB::SIZE));
+            PhysFrame::range(frame(start), frame(end))
+        };
+        let cut = |reserved: &[PhysFrameRange]| cut_reserved(0, 10, reserved.iter().copied()).collect::<Vec<_>>();
+        assert_eq!(cut(&[]), [(0, 10)]);
+        assert_eq!(cut(&[frames(2, 3), frames(5, 8)]), [(0, 2), (3, 5), (8, 10)]);
+        assert_eq!(cut(&[frames(0, 1), frames(9, 12)]), [(1, 9)]);
+        assert_eq!(cut(&[frames(0, 10)]), []);
+        assert_eq!(cut(&[frames(20, 30)]), [(0, 10)]);
+    }
 }
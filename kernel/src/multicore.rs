@@ -1,70 +1,296 @@
-use core::{arch::{asm, naked_asm}, hint, intrinsics::size_of_val, mem::transmute, ptr, sync::atomic::{AtomicU8, Ordering}};
+use core::{intrinsics::size_of_val, ptr, sync::atomic::{AtomicU32, Ordering}};
 
-use acpi::platform::ProcessorInfo;
+use acpi::platform::{ProcessorInfo, ProcessorState};
 use alloc::alloc::Global;
-use x86::apic::{xapic::XAPIC, ApicControl, ApicId};
-use x86_64::{structures::{gdt::{Descriptor, GlobalDescriptorTable}, paging::page}, PhysAddr, VirtAddr};
-
-use crate::{memory::{active_level_4_table, phys_to_virt}, stack::STACK_REFS, x86_ext::FrameNumeric, PAGE_SIZE, PHYS_OFFSET};
+use apic::{msr::ApicBase, Apic, Destination, ErrorStatus, Ipi};
+use x86_64::{
+    instructions::hlt,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        frame::PhysFrameRange, page_table::PageTableEntry, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB,
+        Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
 
-static AP_GDT: GlobalDescriptorTable = {let mut gdt = GlobalDescriptorTable::new();
-    gdt.append(Descriptor::kernel_code_segment());
-    gdt.append(Descriptor::kernel_data_segment());
-    gdt
+use crate::{
+    memory::{phys_to_virt, release_frame, AP_TABLE_FRAMES, TRAMPOLINE_FRAME},
+    exceptions::SPURIOUS_VECTOR,
+    percpu,
+    stack::{alloc_kernel_stack, free_kernel_stack},
+    tsc::{self, SyncSide},
+    MAX_PROC_COUNT, MAX_STACK_SIZE, PAGE_SIZE,
 };
 
 const AP_BOOT_CODE: &[u8; include_bytes!(concat!(core::env!("OUT_DIR"), "/ap_boot.bin")).len()] = include_bytes!(concat!(core::env!("OUT_DIR"), "/ap_boot.bin"));
 
-static AP_COUNTER: AtomicU8 = AtomicU8::new(0);
+/// Number of APs that made it into `ap_main`, the BSP waits on it before starting the next one
+static AP_ONLINE: AtomicU32 = AtomicU32::new(0);
 
+// Offsets into the trampoline's parameter block, see `ap_boot.s`
 const BOOT_OFFSET_ENTRY: u64 = 0x08;
-const BOOT_OFFSET_CPU_ID: u64 = BOOT_OFFSET_ENTRY + 0x08;
-const BOOT_OFFSET_PML4: u64 = BOOT_OFFSET_CPU_ID + 0x04;
-const BOOT_OFFSET_BASE_ADDR: u64 = BOOT_OFFSET_PML4 + 0x04;
-const MMIO_REGION: u64 = 0xFEE00000;
+const BOOT_OFFSET_STACK: u64 = BOOT_OFFSET_ENTRY + 0x08;
+const BOOT_OFFSET_PML4: u64 = BOOT_OFFSET_STACK + 0x08;
+const BOOT_OFFSET_CPU_ID: u64 = BOOT_OFFSET_PML4 + 0x08;
+const BOOT_OFFSET_STARTED: u64 = BOOT_OFFSET_CPU_ID + 0x04;
+const BOOT_OFFSET_KERNEL_CR3: u64 = BOOT_OFFSET_STARTED + 0x04;
 
+const INIT_DELAY_US: u64 = 10_000;
+const SIPI_DELAY_US: u64 = 200;
+const IPI_DELIVERY_TIMEOUT_US: u64 = 1_000;
+/// From the trampoline to `ap_main` signing on, includes allocating the AP's IST stacks
+const AP_ONLINE_TIMEOUT_US: u64 = 500_000;
 
+/// APs that made it into `ap_main` so far
+pub(crate) fn online_aps() -> u32 {
+    AP_ONLINE.load(Ordering::Acquire)
+}
+
+/// Initial APIC ID of the executing core from CPUID, before its per-CPU area knows it
+pub fn initial_apic_id() -> u32 {
+    unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24
+}
 
+/// Entered from the trampoline in long mode on the AP's own stack, still on the low tables
 #[unsafe(no_mangle)]
-pub fn ap_main() -> ! {
-    let cur_ap = AP_COUNTER.fetch_add(1, Ordering::SeqCst);
-    log::info!("AP {} started", cur_ap);
-    //TODO: Setup APIC, interrupt handlers, etc.
-    loop {}
+pub extern "C" fn ap_main(cpu_id: u32, kernel_cr3: u64) -> ! {
+    // The low tables share every kernel mapping, only the identity mapped trampoline goes away
+    let kernel_pml4 = PhysFrame::containing_address(PhysAddr::new(kernel_cr3));
+    unsafe { Cr3::write(kernel_pml4, Cr3Flags::empty()) };
+    percpu::load(cpu_id as usize);
+    crate::gdt::init_core();
+    crate::exceptions::load_idt();
+    init_local_apic();
+    crate::timers::init_core();
+    AP_ONLINE.fetch_add(1, Ordering::Release);
+    crate::trace!("AP {} started", cpu_id);
+    if tsc::check_sync(SyncSide::Ap).is_none() {
+        crate::trace!("AP {}: the BSP never joined the TSC sync check", cpu_id);
+    }
+    loop {
+        hlt();
+    }
 }
 
 pub fn setup_cores(proc_info: ProcessorInfo<Global>) {
     log::debug!("Setting up cores");
-    log::debug!("Switching to APIC mode on BSP");
-    let mmio_region_addr = MMIO_REGION + unsafe {*PHYS_OFFSET.get_unchecked() as u64 };
-    let mmio_region = unsafe { core::slice::from_raw_parts_mut(mmio_region_addr as *mut u32, 0x1000) };
-    let mut bsp_apic = bsp_init_apic(mmio_region);
-    log::debug!("BSP APIC initialized");
     log::debug!("Setting up AP trampoline");
-    let trampoline_addr = PhysAddr::new(0x8000);
+    let Some(trampoline_frame) = TRAMPOLINE_FRAME.get().copied() else {
+        log::error!("No frame below 1 MiB was reserved for the AP trampoline, staying single core");
+        return;
+    };
+    let trampoline_addr = trampoline_frame.start_address();
+    let Some(table_frames) = AP_TABLE_FRAMES.get().copied() else {
+        log::error!("No frames below 4 GiB were reserved for the AP page tables, staying single core");
+        return;
+    };
+    let low_tables = LowPageTables::new(table_frames, trampoline_frame);
     let trampoline = copy_ap_trampoline(phys_to_virt(trampoline_addr));
-    let tampoline_frame: FrameNumeric<PAGE_SIZE> = trampoline_addr.as_u64().try_into().unwrap();
-    log::debug!("Trampoline copied to 0x8000");
-    let pml4 = unsafe { active_level_4_table(VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64)) };
+    let vector = sipi_vector(trampoline_frame);
+    log::debug!("Trampoline copied to {:#x}", trampoline_addr);
+    let kernel_pml4 = Cr3::read().0.start_address();
     log::debug!("Starting APs");
+    log::debug!("Startup vector {:x}", vector);
+    let mut online = 0;
+    // `AP_ONLINE` as of the last handshake, an AP parked after timing out may still have bumped it
+    let mut handshakes = 0;
+    // An AP that failed half way might still be running the trampoline, it can't be reused then
+    let mut trampoline_in_use = false;
     for (i, cpu) in proc_info.application_processors.iter().enumerate() {
-        log::debug!("CPU: {}, State: {:?}", i, cpu.state);
-    }
-    log::debug!("Startup vector {:x}", tampoline_frame.num);
-    for (i, cpu) in proc_info.application_processors.iter().enumerate() {
-        assign_trampoline_params(trampoline, i as u32, PhysAddr::new(pml4 as *const _ as u64));
-        let apic_id = ApicId::XApic(cpu.local_apic_id as u8);
-        unsafe { bsp_apic.ipi_init(apic_id) };
-        for _ in 0..100000 {
-            hint::spin_loop();
+        if cpu.state == ProcessorState::Disabled {
+            log::debug!("CPU: {} (APIC ID {}) is disabled, skipping", i, cpu.local_apic_id);
+            continue;
+        }
+        // The BSP is core 0
+        let index = i + 1;
+        if index >= MAX_PROC_COUNT {
+            log::warn!("Only {} cores are supported, not starting the remaining APs", MAX_PROC_COUNT);
+            break;
         }
-        unsafe { bsp_apic.ipi_startup(apic_id, tampoline_frame.num as u8) };
-        for _ in 0..100000 {
-            hint::spin_loop();
+        // Mapped in full, the AP runs on it before it has an IDT to take a growth fault with
+        let stack = alloc_kernel_stack(MAX_STACK_SIZE as u64, MAX_STACK_SIZE as u64).expect("failed to allocate AP stack");
+        percpu::init_cpu(index, cpu.local_apic_id, Some(stack.stack_ref));
+        assign_trampoline_params(trampoline, index as u32, low_tables.pml4(), kernel_pml4, stack.stack_base);
+        tsc::reset_sync_check();
+        crate::trace!("Sending INIT/SIPI to AP {} (APIC ID {})", i, cpu.local_apic_id);
+        let started = percpu::current()
+            .with_local_apic(|apic| start_ap(apic, cpu.local_apic_id, vector, trampoline))
+            .expect("BSP local APIC not set up");
+        if let Err(err) = started {
+            log::error!("AP {} (APIC ID {}) did not start: {:?}", i, cpu.local_apic_id, err);
+            trampoline_in_use = true;
+            // A late start would run on the stack given back
+            if !park_ap(cpu.local_apic_id) {
+                log::error!("AP {} could not be parked, not starting the remaining APs", i);
+                break;
+            }
+            free_kernel_stack(stack);
+            continue;
+        }
+        // One AP at a time, they all share the trampoline's parameter block
+        if tsc::wait_until(AP_ONLINE_TIMEOUT_US, || AP_ONLINE.load(Ordering::Acquire) > handshakes) {
+            handshakes += 1;
+            online += 1;
+            // The AP is waiting for its turn, before anything slow like logging
+            match tsc::check_sync(SyncSide::Bsp) {
+                Some(0) => {}
+                Some(warp) => log::warn!("AP {} TSC out of step with the BSP's by up to {} cycles", i, warp),
+                None => log::warn!("AP {} never joined the TSC sync check", i),
+            }
+            log::info!("AP {} (APIC ID {}) online", i, cpu.local_apic_id);
+        } else {
+            log::error!("AP {} (APIC ID {}) started but never reached ap_main", i, cpu.local_apic_id);
+            trampoline_in_use = true;
+            // It has yet to read its parameters, the next AP's would be its own. Its stack stays
+            // allocated, it may have run on it already.
+            if !park_ap(cpu.local_apic_id) {
+                log::error!("AP {} could not be parked, not starting the remaining APs", i);
+                break;
+            }
+            // It may have made it to `ap_main` just before the INIT
+            handshakes = AP_ONLINE.load(Ordering::Acquire);
         }
     }
-    for (i, cpu) in proc_info.application_processors.iter().enumerate() {
-        log::debug!("CPU: {}, State: {:?}", i, cpu.state);
+    log::info!("{} of {} APs online", online, proc_info.application_processors.len());
+
+    if trampoline_in_use {
+        log::warn!("Keeping the AP trampoline at {:#x} and its page tables reserved", trampoline_addr);
+        return;
+    }
+    low_tables.release();
+    release_frame(trampoline_frame);
+    log::debug!("AP trampoline frame {:#x} and page tables released", trampoline_addr);
+}
+
+/// The SIPI vector is the page number of the trampoline, which is why it must live below 1 MiB
+fn sipi_vector(frame: PhysFrame) -> u8 {
+    let page = frame.start_address().as_u64() / PAGE_SIZE::SIZE;
+    page.try_into().expect("AP trampoline above 1 MiB")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupError {
+    /// The local APIC kept the IPI pending, the ICR delivery status never went idle
+    DeliveryTimeout,
+    /// The local APIC flagged a send error
+    SendError(ErrorStatus),
+    /// Both SIPIs were delivered but the AP never ran the trampoline
+    NoResponse,
+}
+
+/// MP spec startup: INIT, 10 ms, SIPI, 200 µs, and a second SIPI if the AP hasn't shown up yet
+fn start_ap(apic: &mut impl Apic, apic_id: u32, vector: u8, trampoline: VirtAddr) -> Result<(), StartupError> {
+    let started_flag = (trampoline + BOOT_OFFSET_STARTED).as_mut_ptr::<u32>();
+    unsafe { started_flag.write_volatile(0) };
+    let started = || unsafe { started_flag.read_volatile() } != 0;
+
+    let target = Destination::Physical(apic_id);
+    send_ipi(apic, Ipi::init(target))?;
+    send_ipi(apic, Ipi::init_deassert(target))?;
+    tsc::delay_us(INIT_DELAY_US);
+    for _ in 0..2 {
+        send_ipi(apic, Ipi::startup(target, vector))?;
+        if tsc::wait_until(SIPI_DELAY_US, &started) {
+            return Ok(());
+        }
+    }
+    Err(StartupError::NoResponse)
+}
+
+/// Sends the AP back to waiting for a SIPI, returns whether the INIT was delivered
+fn park_ap(apic_id: u32) -> bool {
+    percpu::current()
+        .with_local_apic(|apic| send_ipi(apic, Ipi::init(Destination::Physical(apic_id))))
+        .expect("BSP local APIC not set up")
+        .is_ok()
+}
+
+fn send_ipi(apic: &mut impl Apic, ipi: Ipi) -> Result<(), StartupError> {
+    // Drop errors latched before this IPI
+    apic.error_status();
+    apic.send_ipi(ipi);
+    if !tsc::wait_until(IPI_DELIVERY_TIMEOUT_US, || !apic.ipi_pending()) {
+        return Err(StartupError::DeliveryTimeout);
+    }
+    let error = apic.error_status();
+    if !error.is_empty() {
+        return Err(StartupError::SendError(error));
+    }
+    Ok(())
+}
+
+/// Page tables the APs turn on paging with. The trampoline loads CR3 in 32 bit mode, so all of them
+/// have to live below 4 GiB, which the kernel's own PML4 doesn't guarantee. They map what the
+/// kernel's tables map, except for the trampoline's page which is identity mapped. Only the path to
+/// that page gets tables of its own, copied from the kernel's with huge pages split up.
+struct LowPageTables {
+    /// PML4, PDPT, PD and PT, see `AP_TABLE_FRAMES`
+    frames: PhysFrameRange,
+}
+
+/// Frames `LowPageTables` is built in, reserved below 4 GiB at boot
+pub(crate) const AP_TABLE_FRAME_COUNT: u64 = 4;
+
+impl LowPageTables {
+    const LIMIT: u64 = 0x1_0000_0000;
+
+    fn new(frames: PhysFrameRange, trampoline: PhysFrame) -> Self {
+        assert_eq!(frames.len(), AP_TABLE_FRAME_COUNT);
+        assert!(frames.end.start_address().as_u64() <= Self::LIMIT, "AP page tables at {:?}, above 4 GiB", frames);
+        let tables = Self { frames };
+
+        let page = Page::<PAGE_SIZE>::containing_address(VirtAddr::new(trampoline.start_address().as_u64()));
+        // Anything the AP touches before switching CR3 must not be shadowed by the identity mapping
+        let ap_main_page = Page::<PAGE_SIZE>::containing_address(VirtAddr::new(ap_main as *const () as u64));
+        assert_ne!(page, ap_main_page, "kernel image maps a page at the trampoline's address");
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let [pml4, pdpt, pd, pt] = [0, 1, 2, 3].map(|i| unsafe { tables.table(i) });
+        let kernel_pml4 = unsafe { &*phys_to_virt(Cr3::read().0.start_address()).as_ptr::<PageTable>() };
+        *pml4 = kernel_pml4.clone();
+        copy_level(&pml4[page.p4_index()], pdpt, Size1GiB::SIZE);
+        pml4[page.p4_index()].set_addr(tables.frame(1).start_address(), flags);
+        copy_level(&pdpt[page.p3_index()], pd, Size2MiB::SIZE);
+        pdpt[page.p3_index()].set_addr(tables.frame(2).start_address(), flags);
+        copy_level(&pd[page.p2_index()], pt, Size4KiB::SIZE);
+        pd[page.p2_index()].set_addr(tables.frame(3).start_address(), flags);
+        pt[page.p1_index()].set_frame(trampoline, flags);
+        tables
+    }
+
+    fn frame(&self, index: usize) -> PhysFrame {
+        self.frames.start + index as u64
+    }
+
+    /// Safety: the returned table aliases the frame, only one reference may exist at a time
+    unsafe fn table(&self, index: usize) -> &'static mut PageTable {
+        unsafe { &mut *phys_to_virt(self.frame(index).start_address()).as_mut_ptr::<PageTable>() }
+    }
+
+    fn pml4(&self) -> PhysAddr {
+        self.frame(0).start_address()
+    }
+
+    /// Hands the reserved frames to the frame allocator, only once no AP can be running on them
+    fn release(self) {
+        self.frames.for_each(release_frame);
+    }
+}
+
+/// Fills `table` with what `entry` maps one level further down: a copy of the table it points to,
+/// the huge page it maps split into `page_size` pages, or nothing
+fn copy_level(entry: &PageTableEntry, table: &mut PageTable, page_size: u64) {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        table.zero();
+    } else if flags.contains(PageTableFlags::HUGE_PAGE) {
+        // 2 MiB pages are still huge, in a page table the bit means PAT instead
+        let split_flags = if page_size == Size4KiB::SIZE { flags - PageTableFlags::HUGE_PAGE } else { flags };
+        for (i, split) in table.iter_mut().enumerate() {
+            split.set_addr(entry.addr() + i as u64 * page_size, split_flags);
+        }
+    } else {
+        *table = unsafe { &*phys_to_virt(entry.addr()).as_ptr::<PageTable>() }.clone();
     }
 }
 
@@ -72,28 +298,157 @@ pub fn copy_ap_trampoline(target: VirtAddr) -> VirtAddr {
     log::debug!("AP trampoline size: {}", unsafe {size_of_val(AP_BOOT_CODE)});
     let aligned_target = target.align_down(4096 as u64);
     log::debug!("Copying AP trampoline to {:#x}", aligned_target);
-    unsafe { ptr::copy_nonoverlapping(AP_BOOT_CODE,aligned_target.as_mut_ptr(), 1)};
+    unsafe { ptr::copy_nonoverlapping(AP_BOOT_CODE.as_ptr(), aligned_target.as_mut_ptr(), AP_BOOT_CODE.len()) };
     log::debug!("AP trampoline copied to {:#x}", aligned_target);
     let code: &mut [u8] = unsafe { core::slice::from_raw_parts_mut(aligned_target.as_mut_ptr(), size_of_val(AP_BOOT_CODE)) };
-    log::debug!("Assigning base addresses for trampoline");
     let ap_main_ptr = ap_main as *const () as u64;
-    let ap_main_ref = &ap_main_ptr.to_le_bytes();
-    log::debug!("AP main pointer: {:x}, IP: {:x}", ap_main_ptr, x86_64::registers::read_rip().as_u64());
-    code[BOOT_OFFSET_ENTRY as usize..BOOT_OFFSET_ENTRY as usize + 8].copy_from_slice(ap_main_ref);
-    code[BOOT_OFFSET_BASE_ADDR as usize..BOOT_OFFSET_BASE_ADDR as usize + 4].copy_from_slice(&(aligned_target.as_u64() as u32).to_le_bytes());
+    log::debug!("AP main pointer: {:x}", ap_main_ptr);
+    code[BOOT_OFFSET_ENTRY as usize..BOOT_OFFSET_ENTRY as usize + 8].copy_from_slice(&ap_main_ptr.to_le_bytes());
     aligned_target
 }
 
-pub fn assign_trampoline_params(trampoline: VirtAddr, cpu_id: u32, pml4: PhysAddr) {
+/// `low_pml4` is what the trampoline enables paging with, `kernel_pml4` what `ap_main` switches to
+pub fn assign_trampoline_params(trampoline: VirtAddr, cpu_id: u32, low_pml4: PhysAddr, kernel_pml4: PhysAddr, stack_top: VirtAddr) {
+    assert!(low_pml4.as_u64() <= u32::MAX as u64, "AP PML4 {:#x} above 4 GiB", low_pml4);
     let code: &mut [u8] = unsafe { core::slice::from_raw_parts_mut(trampoline.as_mut_ptr(), size_of_val(AP_BOOT_CODE)) };
     code[BOOT_OFFSET_CPU_ID as usize..BOOT_OFFSET_CPU_ID as usize + 4].copy_from_slice(&cpu_id.to_le_bytes());
+    code[BOOT_OFFSET_STACK as usize..BOOT_OFFSET_STACK as usize + 8].copy_from_slice(&stack_top.as_u64().to_le_bytes());
+    code[BOOT_OFFSET_PML4 as usize..BOOT_OFFSET_PML4 as usize + 8].copy_from_slice(&low_pml4.as_u64().to_le_bytes());
+    code[BOOT_OFFSET_KERNEL_CR3 as usize..BOOT_OFFSET_KERNEL_CR3 as usize + 8].copy_from_slice(&kernel_pml4.as_u64().to_le_bytes());
+}
 
-    //BUG: Ensure PML4 is within 32-bit address space
-    code[BOOT_OFFSET_PML4 as usize..BOOT_OFFSET_PML4 as usize + 4].copy_from_slice(&(pml4.as_u64() as u32).to_le_bytes());
+/// Enables the executing core's local APIC and hands it to its per-CPU data
+pub(crate) fn init_local_apic() {
+    // Firmware may have moved the xAPIC page away from 0xFEE00000
+    let base = phys_to_virt(PhysAddr::new(ApicBase::read().address));
+    let mut apic = unsafe { apic::local_apic(base.as_mut_ptr()) };
+    apic.enable(SPURIOUS_VECTOR);
+    percpu::current().set_local_apic(apic);
 }
 
-pub fn bsp_init_apic(apic_region: &'static mut [u32]) -> XAPIC {
-    let mut apic = XAPIC::new(apic_region);
-    apic.attach();
-    apic
-}
\ No newline at end of file
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+
+    use x86_64::structures::paging::{OffsetPageTable, Translate};
+
+    use super::*;
+    use crate::{memory::get_active_opt, testing::test_cases, FRAME_ALLOC, PHYS_OFFSET};
+
+    test_cases![
+        trampoline_params_are_placeholders,
+        copy_ap_trampoline_patches_entry,
+        copy_ap_trampoline_copies_everything,
+        trampoline_params_patch_cpu_id,
+        trampoline_params_patch_stack_and_pml4,
+        low_tables_map_trampoline_and_kernel,
+        low_tables_keep_the_trampolines_neighbours,
+        local_apic_id_matches_cpuid,
+    ];
+
+    fn trampoline_params_are_placeholders() {
+        let entry = BOOT_OFFSET_ENTRY as usize;
+        assert!(AP_BOOT_CODE[entry..entry + 8].iter().all(|b| *b == 0));
+    }
+
+    fn with_trampoline_buffer(f: impl FnOnce(VirtAddr)) {
+        let layout = Layout::from_size_align(AP_BOOT_CODE.len(), 4096).unwrap();
+        let buffer = unsafe { alloc_zeroed(layout) };
+        assert!(!buffer.is_null());
+        f(VirtAddr::from_ptr(buffer));
+        unsafe { dealloc(buffer, layout) };
+    }
+
+    fn copy_ap_trampoline_patches_entry() {
+        with_trampoline_buffer(|target| {
+            let trampoline = copy_ap_trampoline(target);
+            assert_eq!(trampoline, target);
+            let code: &[u8] = unsafe { core::slice::from_raw_parts(trampoline.as_ptr(), AP_BOOT_CODE.len()) };
+            let entry = BOOT_OFFSET_ENTRY as usize;
+            let ap_main_ptr = ap_main as *const () as u64;
+            assert_eq!(code[entry..entry + 8], ap_main_ptr.to_le_bytes());
+        });
+    }
+
+    fn trampoline_params_patch_cpu_id() {
+        with_trampoline_buffer(|target| {
+            assign_trampoline_params(target, 7, PhysAddr::new(0x1000), PhysAddr::new(0x2000), VirtAddr::new(0xffff_9000_0010_0000));
+            let code: &[u8] = unsafe { core::slice::from_raw_parts(target.as_ptr(), AP_BOOT_CODE.len()) };
+            let cpu_id = BOOT_OFFSET_CPU_ID as usize;
+            assert_eq!(code[cpu_id..cpu_id + 4], 7u32.to_le_bytes());
+        });
+    }
+
+    fn copy_ap_trampoline_copies_everything() {
+        with_trampoline_buffer(|target| {
+            copy_ap_trampoline(target);
+            let code: &[u8] = unsafe { core::slice::from_raw_parts(target.as_ptr(), AP_BOOT_CODE.len()) };
+            // Everything past the parameter block is copied verbatim
+            let params_end = BOOT_OFFSET_KERNEL_CR3 as usize + 8;
+            assert_eq!(code[params_end..], AP_BOOT_CODE[params_end..]);
+        });
+    }
+
+    fn trampoline_params_patch_stack_and_pml4() {
+        with_trampoline_buffer(|target| {
+            let stack_top = VirtAddr::new(0xffff_9000_0010_0000);
+            assign_trampoline_params(target, 1, PhysAddr::new(0x7f_f000), PhysAddr::new(0x1_2345_6000), stack_top);
+            let code: &[u8] = unsafe { core::slice::from_raw_parts(target.as_ptr(), AP_BOOT_CODE.len()) };
+            let stack = BOOT_OFFSET_STACK as usize;
+            let pml4 = BOOT_OFFSET_PML4 as usize;
+            let kernel_cr3 = BOOT_OFFSET_KERNEL_CR3 as usize;
+            assert_eq!(code[stack..stack + 8], stack_top.as_u64().to_le_bytes());
+            assert_eq!(code[pml4..pml4 + 8], 0x7f_f000u64.to_le_bytes());
+            assert_eq!(code[kernel_cr3..kernel_cr3 + 8], 0x1_2345_6000u64.to_le_bytes());
+        });
+    }
+
+    /// Frames to build tables in, the reserved ones went to the frame allocator after bring-up
+    fn alloc_table_frames() -> PhysFrameRange {
+        let first = unsafe { FRAME_ALLOC.get().unwrap().lock() }.alloc(AP_TABLE_FRAME_COUNT as usize).expect("out of frames");
+        let first = PhysFrame::containing_address(PhysAddr::new(first as u64 * PAGE_SIZE::SIZE));
+        PhysFrame::range(first, first + AP_TABLE_FRAME_COUNT)
+    }
+
+    fn free_table_frames(tables: LowPageTables) {
+        let first = (tables.frames.start.start_address().as_u64() / PAGE_SIZE::SIZE) as usize;
+        unsafe { FRAME_ALLOC.get().unwrap().lock() }.dealloc(first, AP_TABLE_FRAME_COUNT as usize);
+    }
+
+    fn low_tables_map_trampoline_and_kernel() {
+        let trampoline = *TRAMPOLINE_FRAME.get().expect("no trampoline frame reserved");
+        let tables = LowPageTables::new(alloc_table_frames(), trampoline);
+
+        let offset = VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64);
+        let low = unsafe { OffsetPageTable::new(tables.table(0), offset) };
+        let kernel = unsafe { get_active_opt(offset) };
+        let identity = VirtAddr::new(trampoline.start_address().as_u64());
+        assert_eq!(low.translate_addr(identity), Some(trampoline.start_address()));
+        let ap_main_addr = VirtAddr::new(ap_main as *const () as u64);
+        assert_eq!(low.translate_addr(ap_main_addr), kernel.translate_addr(ap_main_addr));
+        free_table_frames(tables);
+    }
+
+    fn low_tables_keep_the_trampolines_neighbours() {
+        let trampoline = *TRAMPOLINE_FRAME.get().expect("no trampoline frame reserved");
+        let tables = LowPageTables::new(alloc_table_frames(), trampoline);
+        let offset = VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64);
+        let low = unsafe { OffsetPageTable::new(tables.table(0), offset) };
+        let kernel = unsafe { get_active_opt(offset) };
+        let identity = trampoline.start_address().as_u64();
+        // Whatever the kernel maps next to the trampoline, down to the same page table
+        for addr in [identity.saturating_sub(PAGE_SIZE::SIZE), identity + PAGE_SIZE::SIZE, identity + Size2MiB::SIZE, identity + Size1GiB::SIZE] {
+            let addr = VirtAddr::new(addr);
+            assert_eq!(low.translate_addr(addr), kernel.translate_addr(addr), "{:#x} differs", addr);
+        }
+        free_table_frames(tables);
+    }
+
+    fn local_apic_id_matches_cpuid() {
+        let (id, version) = percpu::current().with_local_apic(|apic| (apic.id(), apic.version())).unwrap();
+        assert_eq!(id, initial_apic_id());
+        assert!(version.version >= 0x10, "not an integrated APIC: {:#x}", version.version);
+        // Timer, LINT0, LINT1 and error at the very least
+        assert!(version.max_lvt_entry >= 3);
+    }
+}
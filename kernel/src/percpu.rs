@@ -0,0 +1,286 @@
+use core::{
+    arch::asm,
+    cell::{Cell, RefCell, UnsafeCell},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use apic::{msr::GsBase, LocalApic};
+use x86_64::{
+    instructions::interrupts,
+    structures::{
+        paging::{PageSize, PhysFrame},
+        tss::TaskStateSegment,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{memory::StackRef, FRAME_ALLOC, MAX_PROC_COUNT, PAGE_SIZE};
+
+// Every core keeps a `CpuLocal` of its own, and `IA32_GS_BASE` points at it once the core has
+// called `load`. The first field points back at the struct, so finding it is a single `gs:[0]`
+// load. Nothing ever reloads GS after that, there is no user mode to `swapgs` for.
+//
+// The BSP fills in the area of every AP before starting it, the AP only loads it. Fields that
+// change later are cells, they are only ever touched by the owning core.
+
+const FRAME_CACHE_SIZE: usize = 32;
+/// Frames moved between a core's cache and `FRAME_ALLOC` at once
+const FRAME_CACHE_BATCH: usize = 16;
+
+static mut CPU_LOCALS: [CpuLocal; MAX_PROC_COUNT] = [const { CpuLocal::new() }; MAX_PROC_COUNT];
+/// Set once the BSP loaded its area, until then the BSP is the only core running
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+pub struct CpuLocal {
+    /// Must stay the first field, see `current`
+    self_ptr: *const CpuLocal,
+    index: usize,
+    apic_id: u32,
+    current_stack: Cell<StackRef>,
+    tss: Cell<Option<&'static TaskStateSegment>>,
+    local_apic: UnsafeCell<Option<LocalApic>>,
+    frame_cache: RefCell<FrameCache>,
+}
+
+impl CpuLocal {
+    const fn new() -> Self {
+        Self {
+            self_ptr: ptr::null(),
+            index: 0,
+            apic_id: 0,
+            current_stack: Cell::new(StackRef::new(0).unwrap()),
+            tss: Cell::new(None),
+            local_apic: UnsafeCell::new(None),
+            frame_cache: RefCell::new(FrameCache::new()),
+        }
+    }
+
+    /// Dense index of the core, 0 is the BSP
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// The registered stack the core runs on, `None` on the bootloader's stack
+    pub fn current_stack(&self) -> Option<StackRef> {
+        Some(self.current_stack.get()).filter(|stack_ref| stack_ref.as_u16() != 0)
+    }
+
+    pub fn tss(&self) -> Option<&'static TaskStateSegment> {
+        self.tss.get()
+    }
+
+    pub(crate) fn set_tss(&self, tss: &'static TaskStateSegment) {
+        self.tss.set(Some(tss));
+    }
+
+    pub(crate) fn set_local_apic(&self, apic: LocalApic) {
+        interrupts::without_interrupts(|| unsafe { *self.local_apic.get() = Some(apic) });
+    }
+
+    /// Runs `f` on the core's local APIC with interrupts off, `None` if it isn't set up yet.
+    /// Must not be nested, the closure gets the only reference.
+    pub fn with_local_apic<R>(&self, f: impl FnOnce(&mut LocalApic) -> R) -> Option<R> {
+        interrupts::without_interrupts(|| unsafe { (*self.local_apic.get()).as_mut().map(f) })
+    }
+}
+
+/// Single frames kept back per core, so the hot paths don't take the `FRAME_ALLOC` lock
+struct FrameCache {
+    frames: [usize; FRAME_CACHE_SIZE],
+    len: usize,
+}
+
+impl FrameCache {
+    const fn new() -> Self {
+        Self {
+            frames: [0; FRAME_CACHE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            self.refill();
+        }
+        self.len = self.len.checked_sub(1)?;
+        Some(self.frames[self.len])
+    }
+
+    fn push(&mut self, frame: usize) {
+        if self.len == FRAME_CACHE_SIZE {
+            self.drain(FRAME_CACHE_BATCH);
+        }
+        self.frames[self.len] = frame;
+        self.len += 1;
+    }
+
+    /// Gives up if the global allocator is busy, the caller may be interrupting its owner
+    fn refill(&mut self) {
+        let Some(mut frame_alloc) = (unsafe { FRAME_ALLOC.get() }).and_then(|alloc| alloc.try_lock()) else {
+            return;
+        };
+        let Some(first) = frame_alloc.alloc(FRAME_CACHE_BATCH) else {
+            return;
+        };
+        for frame in first..first + FRAME_CACHE_BATCH {
+            self.frames[self.len] = frame;
+            self.len += 1;
+        }
+    }
+
+    fn drain(&mut self, count: usize) {
+        let mut frame_alloc = unsafe { FRAME_ALLOC.get().unwrap().lock() };
+        for _ in 0..count.min(self.len) {
+            self.len -= 1;
+            frame_alloc.dealloc(self.frames[self.len], 1);
+        }
+    }
+}
+
+/// Declares a static with one `T` per core, `get` hands out the executing core's instance.
+/// `T` doesn't have to be `Sync`, every core only ever sees its own slot.
+///
+/// ```ignore
+/// percpu! {
+///     static TICKS: Cell<u64> = Cell::new(0);
+/// }
+/// ```
+macro_rules! percpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::percpu::PerCpu<$ty> =
+            $crate::percpu::PerCpu::new([const { $init }; $crate::MAX_PROC_COUNT]);
+    };
+}
+
+pub(crate) use percpu;
+
+pub struct PerCpu<T> {
+    slots: [T; MAX_PROC_COUNT],
+}
+
+// SAFETY: A slot is only reachable from the core it belongs to, `&T` never crosses cores
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(slots: [T; MAX_PROC_COUNT]) -> Self {
+        Self { slots }
+    }
+
+    pub fn get(&self) -> &T {
+        &self.slots[core_index()]
+    }
+}
+
+/// Prepares the area of core `index`, by the BSP for itself and for every AP before starting it
+pub(crate) fn init_cpu(index: usize, apic_id: u32, stack: Option<StackRef>) {
+    assert!(index < MAX_PROC_COUNT, "core {} has no per-CPU area", index);
+    let cpu = unsafe { &mut CPU_LOCALS[index] };
+    cpu.self_ptr = cpu as *const CpuLocal;
+    cpu.index = index;
+    cpu.apic_id = apic_id;
+    if let Some(stack) = stack {
+        cpu.current_stack.set(stack);
+    }
+}
+
+/// Points the executing core's GS base at the area of core `index`, once per core and before
+/// anything asks for `current`
+pub(crate) fn load(index: usize) {
+    let cpu = unsafe { &CPU_LOCALS[index] };
+    assert_eq!(cpu.self_ptr, cpu as *const _, "per-CPU area {} not initialized", index);
+    unsafe { GsBase::write(VirtAddr::from_ptr(cpu).as_u64()) };
+    LOADED.store(true, Ordering::Release);
+}
+
+/// The executing core's area, only valid after `load` ran on it
+pub fn current() -> &'static CpuLocal {
+    let cpu: *const CpuLocal;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, preserves_flags, readonly)) };
+    unsafe { &*cpu }
+}
+
+/// Index of the executing core, also usable before its area is loaded
+pub fn core_index() -> usize {
+    // Only the BSP runs until it loaded its own area, APs load theirs before anything else
+    if !LOADED.load(Ordering::Acquire) {
+        return 0;
+    }
+    current().index
+}
+
+/// Takes a frame from the executing core's cache, refilling it from `FRAME_ALLOC` when empty.
+/// Fails instead of blocking when the cache or the global allocator is in use, which makes it
+/// safe to call from fault handlers.
+pub(crate) fn alloc_frame() -> Option<PhysFrame<PAGE_SIZE>> {
+    let mut cache = current().frame_cache.try_borrow_mut().ok()?;
+    let frame = cache.pop()?;
+    Some(PhysFrame::containing_address(PhysAddr::new(frame as u64 * PAGE_SIZE::SIZE)))
+}
+
+pub(crate) fn free_frame(frame: PhysFrame<PAGE_SIZE>) {
+    let number = (frame.start_address().as_u64() / PAGE_SIZE::SIZE) as usize;
+    current().frame_cache.borrow_mut().push(number);
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use core::sync::atomic::AtomicU32;
+
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![
+        bsp_is_core_zero,
+        gs_points_at_own_area,
+        percpu_statics_are_per_core,
+        frame_cache_round_trips,
+        frame_cache_fails_while_borrowed,
+    ];
+
+    fn bsp_is_core_zero() {
+        let cpu = current();
+        assert_eq!(cpu.index(), 0);
+        assert_eq!(core_index(), 0);
+        let apic_id = unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24;
+        assert_eq!(cpu.apic_id(), apic_id);
+        assert!(cpu.tss().is_some(), "TSS not recorded");
+    }
+
+    fn gs_points_at_own_area() {
+        let cpu = current();
+        assert_eq!(GsBase::read(), VirtAddr::from_ptr(cpu).as_u64());
+        assert_eq!(cpu.self_ptr, cpu as *const _);
+    }
+
+    percpu! {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+    }
+
+    fn percpu_statics_are_per_core() {
+        COUNTER.get().fetch_add(3, Ordering::Relaxed);
+        assert_eq!(COUNTER.get().load(Ordering::Relaxed), 3);
+        assert_eq!(COUNTER.slots[1].load(Ordering::Relaxed), 0);
+    }
+
+    fn frame_cache_round_trips() {
+        let frame = alloc_frame().expect("no frame from the per-CPU cache");
+        let word = crate::memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+        unsafe { word.write_volatile(0x1234) };
+        assert_eq!(unsafe { word.read_volatile() }, 0x1234);
+        free_frame(frame);
+        assert_eq!(alloc_frame(), Some(frame), "freed frame not reused first");
+        free_frame(frame);
+    }
+
+    fn frame_cache_fails_while_borrowed() {
+        let _cache = current().frame_cache.borrow_mut();
+        assert!(alloc_frame().is_none());
+    }
+}
@@ -0,0 +1,72 @@
+use x86_64::instructions::port::Port;
+
+// Legacy 8259 PIC pair. Only used to route ISA IRQs (serial) until an IOAPIC driver exists,
+// remapped above the exception vectors and masked except for the lines we ask for.
+
+pub const PIC_1_OFFSET: u8 = 0x20;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_2_DATA: u16 = 0xA1;
+
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+const CMD_EOI: u8 = 0x20;
+const CASCADE_IRQ: u8 = 2;
+
+/// Remaps both PICs to `PIC_1_OFFSET`/`PIC_2_OFFSET` and masks every line
+pub unsafe fn remap_and_mask() {
+    let mut cmd1: Port<u8> = Port::new(PIC_1_COMMAND);
+    let mut data1: Port<u8> = Port::new(PIC_1_DATA);
+    let mut cmd2: Port<u8> = Port::new(PIC_2_COMMAND);
+    let mut data2: Port<u8> = Port::new(PIC_2_DATA);
+    // Writes to an unused port give the PICs time to settle on older hardware
+    let mut wait_port: Port<u8> = Port::new(0x80);
+    unsafe {
+        let mut io_wait = || wait_port.write(0);
+        cmd1.write(ICW1_INIT);
+        io_wait();
+        cmd2.write(ICW1_INIT);
+        io_wait();
+        data1.write(PIC_1_OFFSET);
+        io_wait();
+        data2.write(PIC_2_OFFSET);
+        io_wait();
+        data1.write(1 << CASCADE_IRQ);
+        io_wait();
+        data2.write(CASCADE_IRQ);
+        io_wait();
+        data1.write(ICW4_8086);
+        io_wait();
+        data2.write(ICW4_8086);
+        io_wait();
+        data1.write(0xFF);
+        data2.write(0xFF);
+    }
+}
+
+pub fn unmask(irq: u8) {
+    let (port, line) = if irq < 8 { (PIC_1_DATA, irq) } else { (PIC_2_DATA, irq - 8) };
+    let mut data: Port<u8> = Port::new(port);
+    unsafe {
+        let mask = data.read() & !(1 << line);
+        data.write(mask);
+        if irq >= 8 {
+            // The slave only gets through while the cascade line is open
+            let mut data1: Port<u8> = Port::new(PIC_1_DATA);
+            let mask = data1.read() & !(1 << CASCADE_IRQ);
+            data1.write(mask);
+        }
+    }
+}
+
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            Port::<u8>::new(PIC_2_COMMAND).write(CMD_EOI);
+        }
+        Port::<u8>::new(PIC_1_COMMAND).write(CMD_EOI);
+    }
+}
@@ -0,0 +1,56 @@
+use x86_64::instructions::port::Port;
+
+// Legacy 8254 PIT. Channel 2 is free on every PC and can be polled through port 0x61, which makes
+// it the reference of last resort for calibrating the faster time sources.
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const SPEAKER_CONTROL: u16 = 0x61;
+
+const GATE_2: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const OUT_2: u8 = 1 << 5;
+// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// Ticks for a wait of `us` microseconds, clamped to what the 16 bit counter holds
+pub fn ticks_for_us(us: u64) -> u16 {
+    (PIT_FREQUENCY * us / 1_000_000).clamp(1, u16::MAX as u64) as u16
+}
+
+/// Runs `ticks` PIT ticks on channel 2 and calls `started` right after the countdown begins and
+/// `ended` right after it runs out, so callers can sample their own counter at both edges
+pub fn measure(ticks: u16, started: impl FnOnce(), ended: impl FnOnce()) {
+    let mut control: Port<u8> = Port::new(SPEAKER_CONTROL);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL_2_DATA);
+    unsafe {
+        // Gate on, speaker off
+        let value = control.read();
+        control.write((value & !SPEAKER_ENABLE) | GATE_2);
+        command.write(CHANNEL_2_ONE_SHOT);
+        data.write(ticks as u8);
+        data.write((ticks >> 8) as u8);
+        started();
+        while control.read() & OUT_2 == 0 {
+            core::hint::spin_loop();
+        }
+        ended();
+    }
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![ticks_for_us_converts_and_clamps];
+
+    fn ticks_for_us_converts_and_clamps() {
+        assert_eq!(ticks_for_us(10_000), 11_931);
+        assert_eq!(ticks_for_us(0), 1);
+        assert_eq!(ticks_for_us(1_000_000), u16::MAX);
+    }
+}
@@ -0,0 +1,108 @@
+use acpi::{address::AddressSpace, platform::PmTimer};
+use conquer_once::spin::OnceCell;
+use x86_64::{instructions::port::PortReadOnly, PhysAddr, VirtAddr};
+
+use crate::{calibration::ExtendedCounter, memory::phys_to_virt};
+
+// ACPI power management timer, a free running 3.579545 MHz counter every ACPI machine has. Only
+// 24 bits wide on most, so it wraps every 4.7 s and is extended to 64 bits in software. Slower to
+// read than the HPET but the fixed frequency reference to fall back on where there is none.
+
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+
+static PM_TIMER: OnceCell<Block> = OnceCell::uninit();
+static COUNTER: ExtendedCounter = ExtendedCounter::new();
+
+/// Where the `PM_TMR` register is, the FADT allows either address space
+#[derive(Debug, Clone, Copy)]
+enum Register {
+    Port(u16),
+    Memory(VirtAddr),
+}
+
+struct Block {
+    register: Register,
+    /// 24 or 32
+    bits: u32,
+}
+
+// SAFETY: Only a read only register
+unsafe impl Send for Block {}
+unsafe impl Sync for Block {}
+
+impl Block {
+    fn read(&self) -> u32 {
+        let raw = match self.register {
+            Register::Port(port) => unsafe { PortReadOnly::<u32>::new(port).read() },
+            Register::Memory(address) => unsafe { address.as_ptr::<u32>().read_volatile() },
+        };
+        (raw as u64 & ((1 << self.bits) - 1)) as u32
+    }
+}
+
+/// Takes the timer block from the FADT. Leaves `is_present` false if there is none or it sits in
+/// an address space the kernel can't reach.
+pub(crate) fn init(pm_timer: Option<&PmTimer>) {
+    let Some(pm_timer) = pm_timer else {
+        log::info!("No ACPI PM timer");
+        return;
+    };
+    let register = match pm_timer.base.address_space {
+        AddressSpace::SystemIo => match u16::try_from(pm_timer.base.address) {
+            Ok(port) => Register::Port(port),
+            Err(_) => {
+                log::warn!("ACPI PM timer at I/O port {:#x}, out of range", pm_timer.base.address);
+                return;
+            }
+        },
+        AddressSpace::SystemMemory => Register::Memory(phys_to_virt(PhysAddr::new(pm_timer.base.address))),
+        other => {
+            log::warn!("ACPI PM timer in {:?} space, ignoring it", other);
+            return;
+        }
+    };
+    let bits = if pm_timer.supports_32bit { 32 } else { 24 };
+    let block = Block { register, bits };
+    COUNTER.reset(block.read() as u64);
+    log::info!("ACPI PM timer at {:?}, {} bit", register, bits);
+    PM_TIMER.init_once(|| block);
+}
+
+pub fn is_present() -> bool {
+    PM_TIMER.get().is_some()
+}
+
+/// The counter extended to 64 bits, never going backwards. Holds as long as something reads it
+/// at least once per wrap, every 4.7 s with a 24 bit counter.
+pub fn counter() -> u64 {
+    let block = PM_TIMER.get().expect("no ACPI PM timer");
+    COUNTER.extend(block.read() as u64, block.bits)
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / PM_TIMER_FREQUENCY as u128) as u64
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    (ns as u128 * PM_TIMER_FREQUENCY as u128).div_ceil(1_000_000_000) as u64
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{testing::{self, test_cases}, tsc};
+
+    test_cases![counter_runs_at_nominal_frequency];
+
+    fn counter_runs_at_nominal_frequency() {
+        if !is_present() {
+            return testing::skip("no ACPI PM timer");
+        }
+        let (start, tsc_start) = (counter(), tsc::read());
+        tsc::delay_us(2_000);
+        let (elapsed, tsc_elapsed) = (counter() - start, tsc::read() - tsc_start);
+        let tsc_ns = tsc::cycles_to_ns(tsc_elapsed);
+        let elapsed_ns = ticks_to_ns(elapsed);
+        assert!(testing::timing_matches(elapsed_ns, tsc_ns), "PM timer {} ns, TSC {} ns", elapsed_ns, tsc_ns);
+    }
+}
@@ -0,0 +1,39 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::{hlt, interrupts, port::Port};
+
+/// I/O port of the `isa-debug-exit` device the host runner attaches to QEMU
+pub const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+// Written to the debug exit port, QEMU then exits with `(code << 1) | 1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+static EXIT_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Makes the panic handler exit QEMU with `QemuExitCode::Failed` instead of spinning.
+/// Only test kernels should call this, the port means nothing on real hardware.
+pub fn set_exit_on_panic() {
+    EXIT_ON_PANIC.store(true, Ordering::SeqCst);
+}
+
+pub fn exit_on_panic() -> bool {
+    EXIT_ON_PANIC.load(Ordering::SeqCst)
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    // Anything still queued for the UART would be lost when QEMU goes away
+    crate::trace::try_drain();
+    log::logger().flush();
+    let mut port = Port::new(ISA_DEBUG_EXIT_PORT);
+    unsafe { port.write(exit_code as u32) };
+    // Not running under QEMU with the debug exit device, park the core instead
+    interrupts::disable();
+    loop {
+        hlt();
+    }
+}
@@ -0,0 +1,409 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, AtomicU64, Ordering},
+};
+
+use acpi::{fadt::Fadt, AcpiTables};
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::{pic, tsc, OffsetMappedHandler, IDT};
+
+// CMOS real time clock. Its registers come in BCD or binary and 12 or 24 hour format depending on
+// how firmware set it up, and change under the reader once a second. The century lives in a CMOS
+// register only the FADT knows about. The RTC interrupt on ISA IRQ 8 carries the periodic, alarm
+// and update ended events.
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+/// Keeps NMIs masked while an index is selected, a CMOS access must not be split by one
+const NMI_DISABLE: u8 = 1 << 7;
+pub const RTC_IRQ: u8 = 8;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_A: u8 = 0x0A;
+const REG_B: u8 = 0x0B;
+const REG_C: u8 = 0x0C;
+
+const A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const A_RATE: u8 = 0x0F;
+const B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const B_ALARM_INTERRUPT: u8 = 1 << 5;
+const B_BINARY: u8 = 1 << 2;
+const B_24_HOUR: u8 = 1 << 1;
+const C_PERIODIC: u8 = 1 << 6;
+const C_ALARM: u8 = 1 << 5;
+const HOUR_PM: u8 = 1 << 7;
+/// Alarm field value matching any
+const ALARM_ANY: u8 = 0xC0;
+
+/// Base of the periodic interrupt rate, `32768 >> (rate - 1)` Hz
+const RATE_BASE_HZ: u32 = 32_768;
+/// Rates 1 and 2 are reserved, 3 is 8192 Hz
+const FASTEST_RATE: u8 = 3;
+const SLOWEST_RATE: u8 = 15;
+/// An update takes at most 1984 µs after the flag goes up, a second bounds any wait
+const UPDATE_TIMEOUT_US: u64 = 1_000_000;
+
+static CMOS: Mutex<()> = Mutex::new(());
+/// CMOS register holding the century, 0 if the FADT doesn't name one
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static ALARMS: AtomicU64 = AtomicU64::new(0);
+
+/// A calendar date and time of day in UTC, the RTC is assumed to keep UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01T00:00:00Z, dates before it aren't representable
+    pub fn to_unix_seconds(&self) -> Option<u64> {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let seconds = days * 86_400 + self.hour as i64 * 3_600 + self.minute as i64 * 60 + self.second as i64;
+        u64::try_from(seconds).ok()
+    }
+
+    pub fn from_unix_seconds(seconds: u64) -> Self {
+        let (days, of_day) = (seconds / 86_400, seconds % 86_400);
+        let (year, month, day) = civil_from_days(days as i64);
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (of_day / 3_600) as u8,
+            minute: (of_day / 60 % 60) as u8,
+            second: (of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Howard Hinnant's algorithms, exact for the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Caller holds `CMOS` with interrupts off
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(INDEX).write(NMI_DISABLE | register);
+        let value = Port::<u8>::new(DATA).read();
+        Port::<u8>::new(INDEX).write(0);
+        value
+    }
+}
+
+/// Caller holds `CMOS` with interrupts off
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(INDEX).write(NMI_DISABLE | register);
+        Port::<u8>::new(DATA).write(value);
+        Port::<u8>::new(INDEX).write(0);
+    }
+}
+
+fn with_cmos<R>(f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        f()
+    })
+}
+
+/// Time registers as stored, before any format conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl RawTime {
+    fn read(century_register: u8) -> Self {
+        Self {
+            second: read_register(REG_SECONDS),
+            minute: read_register(REG_MINUTES),
+            hour: read_register(REG_HOURS),
+            day: read_register(REG_DAY),
+            month: read_register(REG_MONTH),
+            year: read_register(REG_YEAR),
+            century: if century_register != 0 { read_register(century_register) } else { 0 },
+        }
+    }
+
+    fn decode(self, status_b: u8) -> DateTime {
+        let binary = status_b & B_BINARY != 0;
+        let convert = |value: u8| if binary { value } else { from_bcd(value) };
+        let pm = status_b & B_24_HOUR == 0 && self.hour & HOUR_PM != 0;
+        let mut hour = convert(self.hour & !HOUR_PM);
+        if status_b & B_24_HOUR == 0 {
+            // 12 AM is midnight, 12 PM noon
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+        let year = convert(self.year) as u16;
+        let year = match self.century {
+            0 => 2000 + year,
+            century => convert(century) as u16 * 100 + year,
+        };
+        DateTime {
+            year,
+            month: convert(self.month),
+            day: convert(self.day),
+            hour,
+            minute: convert(self.minute),
+            second: convert(self.second),
+        }
+    }
+}
+
+const fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+const fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Takes the century register from the FADT, routes IRQ 8 through the PIC and clears any stale
+/// interrupt flags. Nothing fires until `set_periodic` or `set_alarm` asks for it.
+pub(crate) fn init(tables: &AcpiTables<OffsetMappedHandler>) {
+    if let Ok(fadt) = tables.find_table::<Fadt>() {
+        let century = fadt.century;
+        CENTURY_REGISTER.store(century, Ordering::Relaxed);
+        if { fadt.iapc_boot_arch }.use_time_and_alarm_namespace_for_rtc() {
+            log::warn!("FADT says the CMOS RTC isn't at its legacy ports, reading it anyway");
+        }
+    }
+    interrupts::without_interrupts(|| {
+        unsafe { IDT[pic::PIC_1_OFFSET + RTC_IRQ].set_handler_fn(rtc_interrupt) };
+        with_cmos(|| {
+            let status_b = read_register(REG_B);
+            write_register(REG_B, status_b & !(B_PERIODIC_INTERRUPT | B_ALARM_INTERRUPT));
+            read_register(REG_C);
+        });
+        pic::unmask(RTC_IRQ);
+    });
+}
+
+/// Reads the clock. Waits out an update in progress and re-reads until two reads agree, so the
+/// fields never mix values from either side of a second.
+pub fn read() -> DateTime {
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+    let read_stable = || {
+        if !tsc::wait_until(UPDATE_TIMEOUT_US, || with_cmos(|| read_register(REG_A)) & A_UPDATE_IN_PROGRESS == 0) {
+            log::warn!("RTC update in progress for over a second");
+        }
+        with_cmos(|| (RawTime::read(century_register), read_register(REG_B)))
+    };
+    let mut last = read_stable();
+    loop {
+        let current = read_stable();
+        if current == last {
+            return current.0.decode(current.1);
+        }
+        last = current;
+    }
+}
+
+/// Enables the periodic interrupt at the closest rate at or below `hz`, between 2 and 8192 Hz.
+/// Returns the rate it runs at.
+pub fn set_periodic(hz: u32) -> u32 {
+    let rate = (FASTEST_RATE..=SLOWEST_RATE)
+        .find(|rate| RATE_BASE_HZ >> (rate - 1) <= hz)
+        .unwrap_or(SLOWEST_RATE);
+    with_cmos(|| {
+        let status_a = read_register(REG_A);
+        write_register(REG_A, (status_a & !A_RATE) | rate);
+        let status_b = read_register(REG_B);
+        write_register(REG_B, status_b | B_PERIODIC_INTERRUPT);
+    });
+    RATE_BASE_HZ >> (rate - 1)
+}
+
+pub fn stop_periodic() {
+    with_cmos(|| {
+        let status_b = read_register(REG_B);
+        write_register(REG_B, status_b & !B_PERIODIC_INTERRUPT);
+    });
+}
+
+/// Fires the alarm interrupt every day at `hour:minute:second`, a `None` field matches any value
+pub fn set_alarm(hour: Option<u8>, minute: Option<u8>, second: Option<u8>) {
+    with_cmos(|| {
+        let status_b = read_register(REG_B);
+        let encode = |value: Option<u8>| match value {
+            None => ALARM_ANY,
+            Some(value) if status_b & B_BINARY != 0 => value,
+            Some(value) => to_bcd(value),
+        };
+        let hour = hour.map(|hour| {
+            if status_b & B_24_HOUR != 0 {
+                return encode(Some(hour));
+            }
+            let pm = if hour >= 12 { HOUR_PM } else { 0 };
+            let twelve_hour = if hour % 12 == 0 { 12 } else { hour % 12 };
+            encode(Some(twelve_hour)) | pm
+        });
+        write_register(REG_SECONDS_ALARM, encode(second));
+        write_register(REG_MINUTES_ALARM, encode(minute));
+        write_register(REG_HOURS_ALARM, hour.unwrap_or(ALARM_ANY));
+        write_register(REG_B, status_b | B_ALARM_INTERRUPT);
+    });
+}
+
+pub fn clear_alarm() {
+    with_cmos(|| {
+        let status_b = read_register(REG_B);
+        write_register(REG_B, status_b & !B_ALARM_INTERRUPT);
+    });
+}
+
+/// Periodic interrupts taken since boot
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Alarm interrupts taken since boot
+pub fn alarms() -> u64 {
+    ALARMS.load(Ordering::Relaxed)
+}
+
+extern "x86-interrupt" fn rtc_interrupt(_frame: InterruptStackFrame) {
+    // Reading C acknowledges, the RTC raises nothing more until it is read
+    let status_c = with_cmos(|| read_register(REG_C));
+    if status_c & C_PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    if status_c & C_ALARM != 0 {
+        ALARMS.fetch_add(1, Ordering::Relaxed);
+    }
+    pic::end_of_interrupt(RTC_IRQ);
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![
+        bcd_round_trips,
+        decodes_bcd_12_hour,
+        decodes_binary_24_hour,
+        unix_seconds_round_trip,
+        read_is_a_plausible_date,
+        periodic_interrupt_ticks,
+        alarm_fires,
+    ];
+
+    fn bcd_round_trips() {
+        for value in 0..100 {
+            assert_eq!(from_bcd(to_bcd(value)), value);
+        }
+        assert_eq!(to_bcd(59), 0x59);
+    }
+
+    fn decodes_bcd_12_hour() {
+        let raw = RawTime { second: 0x05, minute: 0x30, hour: HOUR_PM | 0x12, day: 0x31, month: 0x12, year: 0x99, century: 0x19 };
+        let decoded = raw.decode(0);
+        assert_eq!(decoded, DateTime { year: 1999, month: 12, day: 31, hour: 12, minute: 30, second: 5 });
+        // 12 AM
+        assert_eq!(RawTime { hour: 0x12, ..raw }.decode(0).hour, 0);
+        assert_eq!(RawTime { hour: HOUR_PM | 0x01, ..raw }.decode(0).hour, 13);
+    }
+
+    fn decodes_binary_24_hour() {
+        let raw = RawTime { second: 59, minute: 59, hour: 23, day: 1, month: 2, year: 24, century: 0 };
+        let decoded = raw.decode(B_BINARY | B_24_HOUR);
+        assert_eq!(decoded, DateTime { year: 2024, month: 2, day: 1, hour: 23, minute: 59, second: 59 });
+    }
+
+    fn unix_seconds_round_trip() {
+        let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        assert_eq!(epoch.to_unix_seconds(), Some(0));
+        let leap_day = DateTime { year: 2024, month: 2, day: 29, hour: 12, minute: 34, second: 56 };
+        assert_eq!(leap_day.to_unix_seconds(), Some(1_709_210_096));
+        assert_eq!(DateTime::from_unix_seconds(1_709_210_096), leap_day);
+        assert_eq!(DateTime { year: 1969, ..epoch }.to_unix_seconds(), None);
+        assert_eq!(leap_day.to_string(), "2024-02-29T12:34:56Z");
+    }
+
+    fn read_is_a_plausible_date() {
+        let now = read();
+        assert!((2000..2200).contains(&now.year), "{}", now);
+        assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day), "{}", now);
+        assert!(now.hour < 24 && now.minute < 60 && now.second < 60, "{}", now);
+    }
+
+    fn periodic_interrupt_ticks() {
+        let before = periodic_ticks();
+        assert_eq!(set_periodic(1024), 1024);
+        let ticked = tsc::wait_until(100_000, || periodic_ticks() >= before + 10);
+        stop_periodic();
+        assert!(ticked, "{} periodic ticks", periodic_ticks() - before);
+    }
+
+    fn alarm_fires() {
+        let before = alarms();
+        // Any hour and minute, two seconds on so the second can't pass while it's being set
+        let second = (read().second + 2) % 60;
+        set_alarm(None, None, Some(second));
+        let fired = tsc::wait_until(3_500_000, || alarms() > before);
+        clear_alarm();
+        assert!(fired, "alarm at second {} never fired", second);
+    }
+}
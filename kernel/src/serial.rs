@@ -0,0 +1,364 @@
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::{pic, IDT};
+
+// 16550 UART driver. Ports start out polled so they can log before interrupts exist,
+// `init_interrupts` later moves COM1 to IRQ driven TX/RX through the legacy PIC.
+
+pub static COM1: Mutex<SerialPort> = Mutex::new(SerialPort::new(ComPort::Com1));
+
+const UART_CLOCK: u32 = 115200;
+const FIFO_SIZE: usize = 16;
+const RING_SIZE: usize = 1024;
+
+// Register offsets from the port base
+const REG_DATA: u16 = 0; // DLL when DLAB is set
+const REG_IER: u16 = 1; // DLM when DLAB is set
+const REG_IIR_FCR: u16 = 2;
+const REG_LCR: u16 = 3;
+const REG_MCR: u16 = 4;
+const REG_LSR: u16 = 5;
+const REG_MSR: u16 = 6;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 1 << 7;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+// OUT2 gates the UART's IRQ line on PC hardware
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+const IIR_MODEM_STATUS: u8 = 0b000;
+const IIR_THR_EMPTY: u8 = 0b001;
+const IIR_RX_AVAILABLE: u8 = 0b010;
+const IIR_LINE_STATUS: u8 = 0b011;
+const IIR_RX_TIMEOUT: u8 = 0b110;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const fn base(&self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    /// ISA IRQ line, COM1/COM3 and COM2/COM4 share a line
+    pub const fn irq(&self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+}
+
+/// Number of received bytes that raise an RX interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FifoTrigger {
+    Bytes1 = 0x00,
+    Bytes4 = 0x40,
+    Bytes8 = 0x80,
+    Bytes14 = 0xC0,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub fifo_trigger: FifoTrigger,
+}
+
+impl SerialConfig {
+    pub const fn new() -> Self {
+        Self {
+            baud: UART_CLOCK,
+            fifo_trigger: FifoTrigger::Bytes14,
+        }
+    }
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// The baud rate does not divide the 115200 Hz UART clock
+    InvalidBaud,
+    /// Nothing answered in loopback mode, the port is absent or broken
+    LoopbackFailed,
+}
+
+struct ByteRing {
+    buf: [u8; RING_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl ByteRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RING_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == RING_SIZE {
+            return false;
+        }
+        self.buf[(self.head + self.len) % RING_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % RING_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+pub struct SerialPort {
+    port: ComPort,
+    interrupts: bool,
+    tx: ByteRing,
+    rx: ByteRing,
+}
+
+impl SerialPort {
+    pub const fn new(port: ComPort) -> Self {
+        Self {
+            port,
+            interrupts: false,
+            tx: ByteRing::new(),
+            rx: ByteRing::new(),
+        }
+    }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        unsafe { Port::new(self.port.base() + reg).read() }
+    }
+
+    fn write_reg(&mut self, reg: u16, value: u8) {
+        unsafe { Port::new(self.port.base() + reg).write(value) }
+    }
+
+    /// Programs baud rate, 8N1 framing and the FIFOs, leaving the port polled
+    pub fn init(&mut self, config: SerialConfig) -> Result<(), SerialError> {
+        if config.baud == 0 || UART_CLOCK % config.baud != 0 {
+            return Err(SerialError::InvalidBaud);
+        }
+        let divisor = (UART_CLOCK / config.baud) as u16;
+
+        self.write_reg(REG_IER, 0);
+        self.write_reg(REG_LCR, LCR_DLAB);
+        self.write_reg(REG_DATA, divisor as u8);
+        self.write_reg(REG_IER, (divisor >> 8) as u8);
+        self.write_reg(REG_LCR, LCR_8N1);
+        self.write_reg(
+            REG_IIR_FCR,
+            FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | config.fifo_trigger as u8,
+        );
+
+        self.write_reg(REG_MCR, MCR_RTS | MCR_OUT2 | MCR_LOOPBACK);
+        self.write_reg(REG_DATA, 0xAE);
+        if self.read_reg(REG_DATA) != 0xAE {
+            return Err(SerialError::LoopbackFailed);
+        }
+        self.write_reg(REG_MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+        self.interrupts = false;
+        Ok(())
+    }
+
+    /// Switches to IRQ driven TX/RX, the caller routes `ComPort::irq` to `handle_interrupt`
+    pub fn enable_interrupts(&mut self) {
+        self.interrupts = true;
+        self.write_reg(REG_IER, IER_RX_AVAILABLE | IER_LINE_STATUS);
+    }
+
+    fn thr_empty(&self) -> bool {
+        self.read_reg(REG_LSR) & LSR_THR_EMPTY != 0
+    }
+
+    fn write_polled(&mut self, byte: u8) {
+        while !self.thr_empty() {
+            core::hint::spin_loop();
+        }
+        self.write_reg(REG_DATA, byte);
+    }
+
+    // Moves queued bytes into the FIFO, only valid once THR reported empty
+    fn fill_fifo(&mut self) {
+        for _ in 0..FIFO_SIZE {
+            match self.tx.pop() {
+                Some(byte) => self.write_reg(REG_DATA, byte),
+                None => break,
+            }
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        if !self.interrupts {
+            self.write_polled(byte);
+            return;
+        }
+        while !self.tx.push(byte) {
+            // Queue full, make room the slow way rather than drop output
+            let queued = self.tx.pop().unwrap();
+            self.write_polled(queued);
+        }
+        if self.thr_empty() {
+            self.fill_fifo();
+        }
+        if self.tx.len != 0 {
+            self.write_reg(REG_IER, IER_RX_AVAILABLE | IER_LINE_STATUS | IER_TX_EMPTY);
+        }
+    }
+
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if self.interrupts {
+            return self.rx.pop();
+        }
+        if self.read_reg(REG_LSR) & LSR_DATA_READY != 0 {
+            Some(self.read_reg(REG_DATA))
+        } else {
+            None
+        }
+    }
+
+    /// Blocks until every queued byte has been handed to the UART
+    pub fn flush(&mut self) {
+        while let Some(byte) = self.tx.pop() {
+            self.write_polled(byte);
+        }
+    }
+
+    pub fn handle_interrupt(&mut self) {
+        loop {
+            let iir = self.read_reg(REG_IIR_FCR);
+            if iir & IIR_NO_INTERRUPT != 0 {
+                break;
+            }
+            match (iir >> 1) & 0b111 {
+                IIR_LINE_STATUS => {
+                    self.read_reg(REG_LSR);
+                }
+                IIR_RX_AVAILABLE | IIR_RX_TIMEOUT => {
+                    while self.read_reg(REG_LSR) & LSR_DATA_READY != 0 {
+                        let byte = self.read_reg(REG_DATA);
+                        // Drop input nobody is reading rather than block in an IRQ
+                        let _ = self.rx.push(byte);
+                    }
+                }
+                IIR_THR_EMPTY => {
+                    self.fill_fifo();
+                    if self.tx.len == 0 {
+                        self.write_reg(REG_IER, IER_RX_AVAILABLE | IER_LINE_STATUS);
+                    }
+                }
+                IIR_MODEM_STATUS => {
+                    self.read_reg(REG_MSR);
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Routes COM1's IRQ through the PIC and switches it to interrupt driven TX/RX
+pub fn init_interrupts() {
+    let irq = ComPort::Com1.irq();
+    interrupts::without_interrupts(|| {
+        unsafe {
+            pic::remap_and_mask();
+            IDT[pic::PIC_1_OFFSET + irq].set_handler_fn(com1_interrupt_handler);
+        }
+        COM1.lock().enable_interrupts();
+        pic::unmask(irq);
+    });
+}
+
+extern "x86-interrupt" fn com1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    COM1.lock().handle_interrupt();
+    pic::end_of_interrupt(ComPort::Com1.irq());
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![ring_is_fifo_across_wrap, ring_rejects_push_when_full, com_ports_share_irqs];
+
+    fn ring_is_fifo_across_wrap() {
+        let mut ring = ByteRing::new();
+        for i in 0..RING_SIZE - 1 {
+            assert!(ring.push(i as u8));
+            assert_eq!(ring.pop(), Some(i as u8));
+        }
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+    }
+
+    fn ring_rejects_push_when_full() {
+        let mut ring = ByteRing::new();
+        (0..RING_SIZE).for_each(|i| assert!(ring.push(i as u8)));
+        assert!(!ring.push(0));
+        assert_eq!(ring.pop(), Some(0));
+        assert!(ring.push(0));
+    }
+
+    fn com_ports_share_irqs() {
+        assert_eq!(ComPort::Com1.irq(), ComPort::Com3.irq());
+        assert_eq!(ComPort::Com2.irq(), ComPort::Com4.irq());
+        assert_eq!(ComPort::Com1.base(), 0x3F8);
+    }
+}
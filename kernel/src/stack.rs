@@ -1,24 +1,40 @@
-use core::ops::Index;
+use core::{ops::Index, ptr};
 
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
 use x86_64::{
     PhysAddr, VirtAddr, align_up,
+    instructions::tlb,
     structures::paging::{
-        Mapper, Page, PageSize, PageTableFlags, PhysFrame, mapper::MapToError,
+        Mapper, Page, PageSize, PageTableFlags, PageTableIndex, PhysFrame, Translate,
+        mapper::{MapToError, TranslateResult},
         page::AddressNotAligned,
     },
 };
 
 use crate::{
-    FRAME_ALLOC, MAX_PROC_COUNT,
-    memory::{FrameAllocatorWrapper, StackRef, UncladCustomPageFlags},
+    FRAME_ALLOC, MAX_STACK_COUNT, PAGE_SIZE, PHYS_OFFSET,
+    memory::{
+        FrameAllocatorWrapper, StackRef, UncladCustomPageFlags, active_level_4_table, get_active_opt, leaf_entry,
+        phys_to_virt,
+    },
+    percpu,
     x86_ext::{FrameNumeric, ToFrameNumeric, assert_aligned},
 };
 
-pub(crate) static mut STACK_REFS: [Stack; MAX_PROC_COUNT] = [Stack::empty(); MAX_PROC_COUNT];
+pub(crate) static mut STACK_REFS: [Stack; MAX_STACK_COUNT] = [Stack::empty(); MAX_STACK_COUNT];
+
+/// Start of the virtual range kernel stacks live in, stack `n` tops out at the end of slot `n`
+static STACK_REGION: OnceCell<VirtAddr> = OnceCell::uninit();
+/// Held while taking or freeing a slot of `STACK_REFS`, one is free while its size is 0. Slot 0
+/// stays unused so `Stack::empty` never names a real stack.
+static STACK_REFS_LOCK: Mutex<()> = Mutex::new(());
+const STACK_SLOT_SIZE: u64 = 0x10_0000;
 
 const STACK_PAGE_FLAGS: PageTableFlags =
     PageTableFlags::union(PageTableFlags::WRITABLE, PageTableFlags::PRESENT).mark_as_stack();
 const STACK_GUARD_FLAGS: PageTableFlags = PageTableFlags::empty().mark_as_stack().mark_as_guard();
+const STACK_RESERVED_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.mark_as_stack();
 
 const fn invalid_physframe<S: PageSize>() -> PhysFrame<S> {
     // SAFETY: This is intentionally invalid
@@ -28,6 +44,7 @@ const fn invalid_physframe<S: PageSize>() -> PhysFrame<S> {
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Stack {
     pub(crate) stack_ref: StackRef,
+    /// Highest address of the stack, the initial stack pointer
     pub(crate) stack_base: VirtAddr,
     pub(crate) max_stack_size: usize,
 }
@@ -49,8 +66,10 @@ impl Index<StackRef> for [Stack] {
     }
 }
 
+#[derive(Debug)]
 pub enum StackAllocError<S: PageSize> {
     OutOfFrames,
+    OutOfStackRefs,
     UnableToMap(MapToError<S>),
     AddressNotAligned,
 }
@@ -68,13 +87,20 @@ impl<S: PageSize> From<AddressNotAligned> for StackAllocError<S> {
 }
 
 
+/// Reserves `max_size` bytes of stack ending at `top` with a guard page right below, all tagged
+/// with `stack_ref`. Only the top `initial_size` bytes are backed by frames, the rest is mapped
+/// on first touch by `grow_stack`. Returns the frame backing the lowest initially mapped page.
 pub fn alloc_stack_with_guard<M: Mapper<S>, S: PageSize>(
     initial_size: u64,
+    max_size: u64,
     mut mapper: M,
-    addr: VirtAddr,
+    top: VirtAddr,
     stack_ref: StackRef,
 ) -> Result<PhysFrame<S>, StackAllocError<S>> {
     assert_aligned!(initial_size as u64, S::SIZE);
+    assert_aligned!(max_size, S::SIZE);
+    assert_aligned!(top.as_u64(), S::SIZE);
+    assert!(initial_size <= max_size, "initial stack size exceeds the maximum");
 
     let mut frame_alloc = unsafe { FRAME_ALLOC.get().unwrap().lock() };
     let initial_page_count: FrameNumeric<S> = initial_size.try_into()?;
@@ -85,31 +111,278 @@ pub fn alloc_stack_with_guard<M: Mapper<S>, S: PageSize>(
     );
     let first_frame = first_frame_num.into();
     let mut frame_alloc = FrameAllocatorWrapper(&mut *frame_alloc);
+    let bottom = top - initial_size;
     for i in 0..initial_page_count.into() {
-        let page = Page::containing_address(addr + (i as u64 * S::SIZE));
+        let page = Page::containing_address(bottom + (i as u64 * S::SIZE));
         let frame_num = FrameNumeric::from_num(first_frame_num.num + i);
         let frame = frame_num.into();
-        //CHECK: To flush or not to flush?
         unsafe {
-            let _ = mapper.map_to(
-                page,
-                frame,
-                STACK_PAGE_FLAGS.assign_stack_ref(stack_ref),
-                &mut frame_alloc,
-            )?;
+            mapper
+                .map_to(
+                    page,
+                    frame,
+                    STACK_PAGE_FLAGS.assign_stack_ref(stack_ref),
+                    &mut frame_alloc,
+                )?
+                .flush();
         }
     }
-    let addr_offset: PhysAddr = initial_page_count.into();
-    let guard_page = Page::containing_address(addr + addr_offset.as_u64());
-    unsafe {
-        //CHECK: Will this even page fault?
-        let _ = mapper.map_to(
-            guard_page,
-            invalid_physframe(),
-            STACK_GUARD_FLAGS.assign_stack_ref(stack_ref),
-            &mut frame_alloc,
-        )?;
+    // Neither the reserved pages nor the guard are present, their parent tables must be. Stacks
+    // grow down, so the reserve sits below the mapped window and the guard below the reserve.
+    let limit = top - max_size;
+    let reserved = Page::range(Page::containing_address(limit), Page::containing_address(bottom));
+    let guard_page = Page::containing_address(limit - S::SIZE);
+    let not_present = reserved
+        .map(|page| (page, STACK_RESERVED_FLAGS))
+        .chain(core::iter::once((guard_page, STACK_GUARD_FLAGS)));
+    for (page, flags) in not_present {
+        unsafe {
+            mapper
+                .map_to_with_table_flags(
+                    page,
+                    invalid_physframe(),
+                    flags.assign_stack_ref(stack_ref),
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                    &mut frame_alloc,
+                )?
+                .flush();
+        }
     }
 
     Ok(first_frame)
 }
+
+/// Backs the page containing `addr` with a fresh frame if it is in the reserved part of a stack.
+/// Returns false for any other address, which makes the fault a real one.
+pub(crate) fn grow_stack(addr: VirtAddr) -> bool {
+    let Some(offset) = PHYS_OFFSET.get() else {
+        return false;
+    };
+    let offset = VirtAddr::new(*offset as u64);
+    let Some(entry) = (unsafe { leaf_entry(offset, addr) }) else {
+        return false;
+    };
+    let flags = entry.flags();
+    if flags.contains(PageTableFlags::PRESENT) || flags.is_guard() {
+        return false;
+    }
+    let Some(stack_ref) = flags.stack_ref() else {
+        return false;
+    };
+    // The faulting code may be in the middle of an allocation, that's a fault we can't resolve
+    let Some(frame) = percpu::alloc_frame() else {
+        return false;
+    };
+    unsafe { ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, PAGE_SIZE::SIZE as usize) };
+    entry.set_addr(frame.start_address(), STACK_PAGE_FLAGS.assign_stack_ref(stack_ref));
+    tlb::flush(addr);
+    true
+}
+
+/// Picks an unused higher half PML4 entry for `alloc_kernel_stack`, called once on the BSP
+pub(crate) fn init_stack_region() {
+    let offset = VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64);
+    let pml4 = unsafe { active_level_4_table(offset) };
+    let index = (256..512)
+        .find(|i| pml4[*i].is_unused())
+        .expect("no free PML4 entry for kernel stacks");
+    let region = Page::from_page_table_indices(
+        PageTableIndex::new(index as u16),
+        PageTableIndex::new(0),
+        PageTableIndex::new(0),
+        PageTableIndex::new(0),
+    );
+    STACK_REGION.init_once(|| region.start_address());
+    log::debug!("Kernel stacks at {:#x}", region.start_address());
+}
+
+/// Allocates a stack that starts out with `initial_size` bytes mapped and grows on demand up to
+/// `max_size`, with a guard page below it, and registers it in `STACK_REFS`
+pub(crate) fn alloc_kernel_stack(initial_size: u64, max_size: u64) -> Result<Stack, StackAllocError<PAGE_SIZE>> {
+    let region = *STACK_REGION.get().expect("stack region not initialized");
+    let _guard = STACK_REFS_LOCK.lock();
+    let index = (1..MAX_STACK_COUNT)
+        .find(|index| unsafe { STACK_REFS[*index].max_stack_size } == 0)
+        .ok_or(StackAllocError::OutOfStackRefs)?;
+    let stack_ref = StackRef::new(index as u16).ok_or(StackAllocError::OutOfStackRefs)?;
+    assert!(max_size + PAGE_SIZE::SIZE <= STACK_SLOT_SIZE, "stack of {:#x} bytes does not fit its slot", max_size);
+    let top = region + (stack_ref.as_u16() as u64 + 1) * STACK_SLOT_SIZE;
+    let mapper = unsafe { get_active_opt(VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64)) };
+    alloc_stack_with_guard::<_, PAGE_SIZE>(initial_size, max_size, mapper, top, stack_ref)?;
+    let stack = Stack {
+        stack_ref,
+        stack_base: top,
+        max_stack_size: max_size as usize,
+    };
+    unsafe { STACK_REFS[stack_ref.as_u16() as usize] = stack };
+    Ok(stack)
+}
+
+/// Unmaps `stack` with its reserve and guard page, gives its frames to the executing core's
+/// frame cache and frees its slot in `STACK_REFS`. Nothing may be running on it.
+pub(crate) fn free_kernel_stack(stack: Stack) {
+    let offset = VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64);
+    let _guard = STACK_REFS_LOCK.lock();
+    let guard_page = Page::<PAGE_SIZE>::containing_address(stack.stack_base - stack.max_stack_size as u64 - PAGE_SIZE::SIZE);
+    for page in Page::range(guard_page, Page::containing_address(stack.stack_base)) {
+        let Some(entry) = (unsafe { leaf_entry(offset, page.start_address()) }) else {
+            continue;
+        };
+        if entry.flags().contains(PageTableFlags::PRESENT) {
+            percpu::free_frame(PhysFrame::containing_address(entry.addr()));
+        }
+        entry.set_unused();
+        tlb::flush(page.start_address());
+    }
+    unsafe { STACK_REFS[stack.stack_ref.as_u16() as usize] = Stack::empty() };
+}
+
+/// The registered stack whose guard page `addr` falls in, `None` for any other address
+pub(crate) fn guard_page_stack(addr: VirtAddr) -> Option<Stack> {
+    let mapper = unsafe { get_active_opt(VirtAddr::new(*PHYS_OFFSET.get()? as u64)) };
+    let TranslateResult::Mapped { flags, .. } = mapper.translate(addr) else {
+        return None;
+    };
+    if !flags.is_guard() {
+        return None;
+    }
+    let stack_ref = flags.stack_ref()?;
+    let stack = unsafe { STACK_REFS.get(stack_ref.as_u16() as usize) }?;
+    Some(Stack { stack_ref, ..*stack })
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use x86_64::structures::paging::Size4KiB;
+
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![
+        empty_stack_is_zeroed,
+        stack_refs_index_by_stack_ref,
+        alloc_stack_maps_stack_and_guard_pages,
+        kernel_stacks_are_registered,
+        guard_page_names_its_stack,
+        reserved_stack_pages_grow_on_demand,
+        freed_stacks_are_unmapped_and_reused,
+    ];
+
+    // Far away from anything the bootloader maps for us
+    const TEST_STACK_TOP: u64 = 0x5555_0001_0000;
+
+    fn empty_stack_is_zeroed() {
+        let stack = Stack::empty();
+        assert_eq!(stack.stack_ref.as_u16(), 0);
+        assert!(stack.stack_base.is_null());
+        assert_eq!(stack.max_stack_size, 0);
+    }
+
+    fn stack_refs_index_by_stack_ref() {
+        let mut stacks = [Stack::empty(); 4];
+        stacks[2].max_stack_size = 0x4000;
+        let stack_ref = StackRef::new(2).unwrap();
+        assert_eq!(stacks[..][stack_ref].max_stack_size, 0x4000);
+    }
+
+    fn alloc_stack_maps_stack_and_guard_pages() {
+        let offset = VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64);
+        let stack_ref = StackRef::new(0x3F0).unwrap();
+        let top = VirtAddr::new(TEST_STACK_TOP);
+        let size = 2 * Size4KiB::SIZE;
+        let mapper = unsafe { get_active_opt(offset) };
+        assert!(alloc_stack_with_guard::<_, Size4KiB>(size, size, mapper, top, stack_ref).is_ok());
+        let addr = top - size;
+
+        let mapper = unsafe { get_active_opt(offset) };
+        for page in 0..2 {
+            match mapper.translate(addr + page * Size4KiB::SIZE) {
+                TranslateResult::Mapped { flags, .. } => {
+                    assert!(flags.is_stack());
+                    assert!(!flags.is_guard());
+                    assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+                    assert_eq!((flags.bits() >> 52) & 0x3FF, 0x3F0);
+                }
+                _ => panic!("stack page {} not mapped", page),
+            }
+        }
+        match mapper.translate(addr - Size4KiB::SIZE) {
+            TranslateResult::Mapped { flags, .. } => {
+                assert!(flags.is_guard());
+                assert!(!flags.contains(PageTableFlags::PRESENT));
+            }
+            _ => panic!("guard page not mapped"),
+        }
+
+        // The stack itself must be usable memory
+        let words: &mut [u64] = unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr(), size as usize / 8) };
+        words.iter_mut().enumerate().for_each(|(i, w)| *w = i as u64);
+        assert!(words.iter().enumerate().all(|(i, w)| *w == i as u64));
+    }
+
+    fn kernel_stacks_are_registered() {
+        let first = alloc_kernel_stack(Size4KiB::SIZE, Size4KiB::SIZE).unwrap();
+        let second = alloc_kernel_stack(2 * Size4KiB::SIZE, 2 * Size4KiB::SIZE).unwrap();
+        assert_ne!(first.stack_ref.as_u16(), second.stack_ref.as_u16());
+        let registered = unsafe { STACK_REFS[second.stack_ref] };
+        assert_eq!(registered.stack_base, second.stack_base);
+        assert_eq!(registered.max_stack_size, 2 * Size4KiB::SIZE as usize);
+        // The top word of the stack is the first one pushed
+        let top_word = (second.stack_base - 8u64).as_mut_ptr::<u64>();
+        unsafe { top_word.write_volatile(0x5a5a) };
+        assert_eq!(unsafe { top_word.read_volatile() }, 0x5a5a);
+        free_kernel_stack(first);
+        free_kernel_stack(second);
+    }
+
+    fn guard_page_names_its_stack() {
+        let size = Size4KiB::SIZE;
+        let stack = alloc_kernel_stack(size, size).unwrap();
+        let guard = stack.stack_base - size - 1u64;
+        let found = guard_page_stack(guard).expect("guard page not recognised");
+        assert_eq!(found.stack_ref.as_u16(), stack.stack_ref.as_u16());
+        assert_eq!(found.max_stack_size, stack.max_stack_size);
+        assert!(guard_page_stack(stack.stack_base - 8u64).is_none());
+        assert!(guard_page_stack(VirtAddr::new(guard_page_stack as *const () as u64)).is_none());
+        free_kernel_stack(stack);
+    }
+
+    fn reserved_stack_pages_grow_on_demand() {
+        let offset = VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64);
+        let page = Size4KiB::SIZE;
+        let stack = alloc_kernel_stack(page, 4 * page).unwrap();
+        let lowest = stack.stack_base - 4 * page;
+        let is_present = |addr: VirtAddr| match unsafe { get_active_opt(offset) }.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(PageTableFlags::PRESENT),
+            _ => false,
+        };
+        assert!(is_present(stack.stack_base - 8u64));
+        assert!(!is_present(lowest));
+
+        // Touching the reserve faults, the page fault handler maps it and the write goes through
+        let word = lowest.as_mut_ptr::<u64>();
+        unsafe { word.write_volatile(0xfeed) };
+        assert_eq!(unsafe { word.read_volatile() }, 0xfeed);
+        assert!(is_present(lowest));
+        assert!(!grow_stack(lowest), "present page grown again");
+        // The limit stays where it was
+        assert!(guard_page_stack(lowest - 1u64).is_some());
+        free_kernel_stack(stack);
+    }
+
+    fn freed_stacks_are_unmapped_and_reused() {
+        let offset = VirtAddr::new(*PHYS_OFFSET.get().unwrap() as u64);
+        let page = Size4KiB::SIZE;
+        let stack = alloc_kernel_stack(2 * page, 4 * page).unwrap();
+        free_kernel_stack(stack);
+        let mapper = unsafe { get_active_opt(offset) };
+        for addr in [stack.stack_base - 8u64, stack.stack_base - 4 * page, stack.stack_base - 5 * page] {
+            assert!(matches!(mapper.translate(addr), TranslateResult::NotMapped), "{:#x} still mapped", addr);
+        }
+        assert_eq!(unsafe { STACK_REFS[stack.stack_ref] }.max_stack_size, 0);
+        let again = alloc_kernel_stack(page, page).unwrap();
+        assert_eq!(again.stack_ref.as_u16(), stack.stack_ref.as_u16());
+        assert_eq!(again.stack_base, stack.stack_base);
+        free_kernel_stack(again);
+    }
+}
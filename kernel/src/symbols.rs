@@ -0,0 +1,128 @@
+use core::fmt;
+
+use crate::crash::KERNEL_IMAGE_OFFSET;
+
+// Address to symbol table embedded in the kernel image. The section is reserved here with an
+// empty table, the host build script then overwrites it in the linked ELF with the real table:
+//
+//   header:  magic "USYM", entry count (u32), string bytes (u32), reserved (u32)
+//   entries: sorted by address { address (u64), size (u32), name offset (u32), name length (u32), reserved (u32) }
+//   strings: demangled names, not NUL terminated
+//
+// Addresses are ELF virtual addresses, so runtime addresses need `KERNEL_IMAGE_OFFSET` removed.
+
+pub const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+pub const SYMBOL_TABLE_MAGIC: [u8; 4] = *b"USYM";
+const HEADER_LEN: usize = 16;
+const ENTRY_LEN: usize = 24;
+
+#[used]
+#[unsafe(link_section = ".unclad_symbols")]
+static SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = {
+    // Non-zero so the section keeps file backed bytes for the build script to patch
+    let mut table = [0; SYMBOL_TABLE_SIZE];
+    table[0] = SYMBOL_TABLE_MAGIC[0];
+    table[1] = SYMBOL_TABLE_MAGIC[1];
+    table[2] = SYMBOL_TABLE_MAGIC[2];
+    table[3] = SYMBOL_TABLE_MAGIC[3];
+    table
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// Formats an address as `function+offset`, or `?` when it isn't in the table
+#[derive(Debug, Clone, Copy)]
+pub struct Resolve(pub u64);
+
+impl fmt::Display for Resolve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match resolve(self.0) {
+            Some(symbol) => write!(f, "{}", symbol),
+            None => write!(f, "?"),
+        }
+    }
+}
+
+fn table() -> &'static [u8] {
+    // The compiler only knows the empty placeholder, hide the contents from it
+    let base = core::hint::black_box(SYMBOL_TABLE.as_ptr());
+    unsafe { core::slice::from_raw_parts(base, SYMBOL_TABLE_SIZE) }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// Resolves a runtime address to the function containing it
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    let table = table();
+    if table[..4] != SYMBOL_TABLE_MAGIC {
+        return None;
+    }
+    let count = read_u32(table, 4) as usize;
+    let strings_start = HEADER_LEN + count * ENTRY_LEN;
+    if strings_start > SYMBOL_TABLE_SIZE {
+        return None;
+    }
+    let elf_addr = addr.wrapping_sub(KERNEL_IMAGE_OFFSET.get().copied().unwrap_or(0));
+    let entry_addr = |i: usize| read_u64(table, HEADER_LEN + i * ENTRY_LEN);
+
+    // First entry past `elf_addr`, the candidate is the one before it
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if entry_addr(mid) <= elf_addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let index = low.checked_sub(1)?;
+    let entry = HEADER_LEN + index * ENTRY_LEN;
+    let offset = elf_addr - entry_addr(index);
+    let size = read_u32(table, entry + 8) as u64;
+    if size != 0 && offset >= size {
+        return None;
+    }
+    let name_start = strings_start + read_u32(table, entry + 12) as usize;
+    let name_len = read_u32(table, entry + 16) as usize;
+    let name = table.get(name_start..name_start + name_len)?;
+    Some(Symbol {
+        name: core::str::from_utf8(name).ok()?,
+        offset,
+    })
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![table_is_patched_by_build, resolves_own_function];
+
+    fn table_is_patched_by_build() {
+        assert_eq!(table()[..4], SYMBOL_TABLE_MAGIC);
+        assert!(read_u32(table(), 4) > 0, "symbol table is empty");
+    }
+
+    fn resolves_own_function() {
+        let addr = resolve as *const () as u64;
+        let symbol = resolve(addr + 1).expect("own address not in symbol table");
+        assert!(symbol.name.ends_with("symbols::resolve"), "resolved to {}", symbol.name);
+        assert_eq!(symbol.offset, 1);
+    }
+}
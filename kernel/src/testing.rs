@@ -0,0 +1,109 @@
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use crate::qemu::{self, QemuExitCode};
+
+// In-kernel unit tests, only built with the `kernel-tests` feature the test kernels require. Each
+// module lists its tests with `test_cases!` in a `tests` submodule, which also registers the list
+// in the `unclad_tests` link section, and `run_all` runs every list found there once the kernel
+// has finished init. A test left out of its module's list is dead code, which the lints catch.
+
+pub struct TestCase {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+/// Declares the `TESTS` slice of a test module, naming each case after its module path, and
+/// registers it with `run_all`
+macro_rules! test_cases {
+    ($($test:ident),* $(,)?) => {
+        pub(crate) const TESTS: &[$crate::testing::TestCase] = &[
+            $($crate::testing::TestCase {
+                name: concat!(module_path!(), "::", stringify!($test)),
+                run: $test,
+            }),*
+        ];
+
+        // Kept by the linker even though only `__start_`/`__stop_unclad_tests` refer to it
+        #[used(linker)]
+        #[unsafe(link_section = "unclad_tests")]
+        static SUITE: &[$crate::testing::TestCase] = TESTS;
+    };
+}
+
+pub(crate) use test_cases;
+
+unsafe extern "C" {
+    // Provided by the linker around every suite `test_cases!` registered, only their addresses
+    // mean anything
+    static __start_unclad_tests: u8;
+    static __stop_unclad_tests: u8;
+}
+
+fn suites() -> &'static [&'static [TestCase]] {
+    unsafe {
+        let start = (&raw const __start_unclad_tests).cast::<&'static [TestCase]>();
+        let stop = (&raw const __stop_unclad_tests).cast::<&'static [TestCase]>();
+        core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+static CURRENT_TEST: AtomicPtr<TestCase> = AtomicPtr::new(ptr::null_mut());
+static CURRENT_SKIPPED: AtomicBool = AtomicBool::new(false);
+
+/// How far, in percent, two clocks timing the same interval may disagree in a test. QEMU's TCG
+/// timing is noisy.
+pub const TIMING_TOLERANCE_PERCENT: u64 = 10;
+
+/// Whether `measured` is within `TIMING_TOLERANCE_PERCENT` of `expected`
+pub fn timing_matches(measured: u64, expected: u64) -> bool {
+    measured.abs_diff(expected) < expected * TIMING_TOLERANCE_PERCENT / 100
+}
+
+/// Marks the running test as skipped because the machine lacks what it needs, the test returns
+/// right after: `return testing::skip("no HPET");`
+pub fn skip(reason: &str) {
+    let test = CURRENT_TEST.load(Ordering::SeqCst);
+    assert!(!test.is_null(), "skip outside a test");
+    // SAFETY: Only ever set to an entry of a registered suite, which are 'static
+    let test = unsafe { &*test };
+    CURRENT_SKIPPED.store(true, Ordering::SeqCst);
+    log::info!("test {} ... skipped, {}", test.name, reason);
+}
+
+/// Runs every registered test and exits QEMU, a panicking test ends the run as a failure
+pub fn run_all() -> ! {
+    qemu::set_exit_on_panic();
+    let count: usize = suites().iter().map(|suite| suite.len()).sum();
+    log::info!("Running {} kernel tests", count);
+    let mut skipped = 0;
+    for test in suites().iter().flat_map(|suite| suite.iter()) {
+        CURRENT_TEST.store(test as *const _ as *mut _, Ordering::SeqCst);
+        CURRENT_SKIPPED.store(false, Ordering::SeqCst);
+        log::info!("test {} ...", test.name);
+        (test.run)();
+        if CURRENT_SKIPPED.load(Ordering::SeqCst) {
+            skipped += 1;
+        } else {
+            log::info!("test {} ... ok", test.name);
+        }
+    }
+    CURRENT_TEST.store(ptr::null_mut(), Ordering::SeqCst);
+    log::info!("test result: ok. {} passed, {} skipped", count - skipped, skipped);
+    qemu::exit_qemu(QemuExitCode::Success);
+}
+
+/// Called from `crash::fatal`, names the test that was running when the kernel went down
+pub(crate) fn report_failure(message: &dyn fmt::Display) {
+    let test = CURRENT_TEST.load(Ordering::SeqCst);
+    if test.is_null() {
+        return;
+    }
+    // SAFETY: Only ever set to an entry of a registered suite, which are 'static
+    let test = unsafe { &*test };
+    log::error!("test {} ... FAILED", test.name);
+    log::error!("{}", message);
+    log::error!("test result: FAILED");
+}
@@ -0,0 +1,266 @@
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub use core::time::Duration;
+use conquer_once::spin::OnceCell;
+
+use crate::{
+    rtc::{self, DateTime},
+    tsc,
+};
+
+// Monotonic time since boot with nanosecond resolution, read from the TSC on whichever core asks.
+// Where the TSCs can't be trusted to agree, across cores or over time, readings go through a
+// shared high water mark so time still never runs backwards. Wall clock time is the monotonic
+// clock shifted by where the RTC put boot, so it never jumps either.
+
+/// TSC value at `init`, time zero
+static BOOT_TSC: OnceCell<u64> = OnceCell::uninit();
+/// Latest reading handed out, only kept while `tsc::is_reliable` is false
+static LATEST_NS: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds since the Unix epoch at `Instant::BOOT`
+static BOOT_UNIX_NS: OnceCell<u64> = OnceCell::uninit();
+
+/// Starts the clock, the TSC must be calibrated
+pub(crate) fn init() {
+    BOOT_TSC.init_once(tsc::read);
+}
+
+/// Anchors the wall clock to the RTC, which only counts whole seconds
+pub(crate) fn init_wall_clock() {
+    let date_time = rtc::read();
+    let since_boot = monotonic_now().as_nanos();
+    let Some(unix_seconds) = date_time.to_unix_seconds() else {
+        log::warn!("RTC reads {}, before the Unix epoch, no wall clock", date_time);
+        return;
+    };
+    BOOT_UNIX_NS.init_once(|| (unix_seconds * 1_000_000_000).saturating_sub(since_boot));
+    log::info!("Wall clock set to {}", date_time);
+}
+
+/// A point on the monotonic clock, nanoseconds since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+pub fn monotonic_now() -> Instant {
+    let boot = *BOOT_TSC.get().expect("clock not started");
+    let ns = tsc::cycles_to_ns(tsc::read().saturating_sub(boot));
+    if tsc::is_reliable() {
+        return Instant(ns);
+    }
+    Instant(LATEST_NS.fetch_max(ns, Ordering::Relaxed).max(ns))
+}
+
+impl Instant {
+    /// Time zero, when the clock started
+    pub const BOOT: Instant = Instant(0);
+
+    pub fn now() -> Self {
+        monotonic_now()
+    }
+
+    pub const fn from_nanos(ns: u64) -> Self {
+        Self(ns)
+    }
+
+    /// Nanoseconds since boot
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Zero if `earlier` is actually later
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or(Duration::ZERO)
+    }
+
+    pub fn checked_duration_since(self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    pub fn elapsed(self) -> Duration {
+        monotonic_now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        let ns = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(ns).map(Instant)
+    }
+
+    pub fn checked_sub(self, duration: Duration) -> Option<Instant> {
+        let ns = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(ns).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow subtracting a duration from an instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// A point in wall clock time, nanoseconds since the Unix epoch in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(u64);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime(0);
+
+    /// Panics before the wall clock was set, see `try_now`
+    pub fn now() -> Self {
+        Self::try_now().expect("wall clock not set")
+    }
+
+    /// `None` until the RTC was read during boot
+    pub fn try_now() -> Option<Self> {
+        let boot = *BOOT_UNIX_NS.get()?;
+        Some(Self(boot + monotonic_now().as_nanos()))
+    }
+
+    pub const fn from_unix_nanos(ns: u64) -> Self {
+        Self(ns)
+    }
+
+    pub const fn as_unix_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Zero if `earlier` is actually later
+    pub fn duration_since(self, earlier: SystemTime) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or(Duration::ZERO)
+    }
+
+    pub fn checked_duration_since(self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<SystemTime> {
+        let ns = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(ns).map(SystemTime)
+    }
+
+    pub fn checked_sub(self, duration: Duration) -> Option<SystemTime> {
+        let ns = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(ns).map(SystemTime)
+    }
+
+    pub fn to_date_time(self) -> DateTime {
+        DateTime::from_unix_seconds(self.0 / 1_000_000_000)
+    }
+}
+
+/// ISO 8601 with milliseconds, `2024-02-29T12:34:56.789Z`
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let date_time = self.to_date_time();
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            date_time.year,
+            date_time.month,
+            date_time.day,
+            date_time.hour,
+            date_time.minute,
+            date_time.second,
+            self.0 / 1_000_000 % 1_000
+        )
+    }
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![
+        now_never_goes_back,
+        elapsed_matches_delay,
+        instant_arithmetic,
+        wall_clock_follows_rtc,
+        system_time_formats_as_iso_8601,
+    ];
+
+    fn now_never_goes_back() {
+        let mut last = Instant::now();
+        for _ in 0..1_000 {
+            let now = Instant::now();
+            assert!(now >= last, "{:?} after {:?}", now, last);
+            last = now;
+        }
+        assert!(last > Instant::BOOT);
+    }
+
+    fn elapsed_matches_delay() {
+        let start = monotonic_now();
+        tsc::delay_us(2_000);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_micros(2_000), "{:?}", elapsed);
+        // Generous, an interrupt may land in between
+        assert!(elapsed < Duration::from_millis(50), "{:?}", elapsed);
+    }
+
+    fn instant_arithmetic() {
+        let instant = Instant::from_nanos(5_000);
+        let later = instant + Duration::from_micros(3);
+        assert_eq!(later.as_nanos(), 8_000);
+        assert_eq!(later - instant, Duration::from_micros(3));
+        assert_eq!(instant - later, Duration::ZERO);
+        assert_eq!(later - Duration::from_nanos(8_000), Instant::BOOT);
+        assert_eq!(instant.checked_sub(Duration::from_micros(6)), None);
+        assert_eq!(instant.checked_add(Duration::MAX), None);
+        let mut moving = instant;
+        moving += Duration::from_nanos(1);
+        moving -= Duration::from_nanos(2);
+        assert_eq!(moving.as_nanos(), 4_999);
+    }
+
+    fn wall_clock_follows_rtc() {
+        let now = SystemTime::now();
+        let rtc = rtc::read().to_unix_seconds().unwrap();
+        // The RTC only has whole seconds, and one may tick over between the two reads
+        assert!(now.as_unix_nanos() / 1_000_000_000 >= rtc.saturating_sub(1), "{} vs {}", now, rtc);
+        assert!(now.as_unix_nanos() / 1_000_000_000 <= rtc + 2, "{} vs {}", now, rtc);
+        assert!(SystemTime::now() >= now);
+    }
+
+    fn system_time_formats_as_iso_8601() {
+        let time = SystemTime::from_unix_nanos(1_709_210_096_789_000_000);
+        assert_eq!(time.to_string(), "2024-02-29T12:34:56.789Z");
+        assert_eq!(SystemTime::UNIX_EPOCH.to_string(), "1970-01-01T00:00:00.000Z");
+        assert_eq!(time.duration_since(SystemTime::UNIX_EPOCH), Duration::from_nanos(1_709_210_096_789_000_000));
+    }
+}
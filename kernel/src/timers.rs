@@ -0,0 +1,222 @@
+use core::cell::Cell;
+
+use apic::{msr::TscDeadline, Apic, Lvt, LvtEntry, TimerDivide, TimerMode};
+use conquer_once::spin::OnceCell;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{
+    calibration::{self, Reference},
+    percpu::{self, percpu},
+    tsc, IDT,
+};
+
+// Local APIC timer, one per core. The bus clock it counts is the same on every core, so it is
+// calibrated once on the BSP. Each core then runs it one-shot, periodic or, where the CPU has it,
+// in TSC-deadline mode. `arm_deadline` is the interface for anything that wants to be woken at a
+// point in time, it emulates TSC-deadline mode with one-shot counts where it is missing.
+
+pub(crate) const TIMER_VECTOR: u8 = 0xF0;
+const DIVIDE: TimerDivide = TimerDivide::By16;
+const CALIBRATION_US: u64 = 10_000;
+const CALIBRATION_ROUNDS: usize = 3;
+
+/// Timer ticks per second with `DIVIDE` applied
+static TIMER_HZ: OnceCell<u64> = OnceCell::uninit();
+static TSC_DEADLINE: OnceCell<bool> = OnceCell::uninit();
+
+struct CoreTimer {
+    /// TSC value the armed deadline is at, 0 if none is
+    deadline: Cell<u64>,
+    expirations: Cell<u64>,
+}
+
+percpu! {
+    static TIMER: CoreTimer = CoreTimer {
+        deadline: Cell::new(0),
+        expirations: Cell::new(0),
+    };
+}
+
+/// Calibrates the timer against the best reference and installs its interrupt handler. Needs the
+/// BSP's local APIC.
+pub(crate) fn init() {
+    let hz = percpu::current()
+        .with_local_apic(|apic| {
+            apic.set_lvt(LvtEntry::Timer, Lvt::masked());
+            apic.set_timer_divide(DIVIDE);
+            apic.set_timer_initial_count(u32::MAX);
+            let elapsed = || (u32::MAX - apic.timer_current_count()) as u64;
+            let hz = calibration::frequency(Reference::best(), CALIBRATION_US, CALIBRATION_ROUNDS, elapsed);
+            apic.set_timer_initial_count(0);
+            hz
+        })
+        .expect("local APIC not set up");
+    TIMER_HZ.init_once(|| hz);
+    // CPUID.01H:ECX.TSC_Deadline
+    let tsc_deadline = unsafe { core::arch::x86_64::__cpuid(1) }.ecx & (1 << 24) != 0;
+    TSC_DEADLINE.init_once(|| tsc_deadline);
+    unsafe { IDT[TIMER_VECTOR].set_handler_fn(timer_interrupt) };
+    log::info!("APIC timer calibrated at {} kHz, TSC-deadline mode: {}", hz / 1000, tsc_deadline);
+}
+
+/// Puts the executing core's timer in a known, stopped state. `init` must have run on the BSP.
+pub(crate) fn init_core() {
+    percpu::current().with_local_apic(|apic| {
+        apic.set_timer_initial_count(0);
+        apic.set_lvt(LvtEntry::Timer, Lvt::masked());
+        apic.set_timer_divide(DIVIDE);
+    });
+}
+
+/// Timer ticks per second
+pub fn frequency() -> u64 {
+    *TIMER_HZ.get().expect("APIC timer not calibrated")
+}
+
+/// Whether `arm_deadline` uses the CPU's TSC-deadline mode rather than emulating it
+pub fn has_tsc_deadline() -> bool {
+    TSC_DEADLINE.get().copied().unwrap_or(false)
+}
+
+/// Timer ticks for `ns`, at least one so the timer actually starts
+pub fn ns_to_ticks(ns: u64) -> u32 {
+    let ticks = ns as u128 * frequency() as u128 / 1_000_000_000;
+    ticks.clamp(1, u32::MAX as u128) as u32
+}
+
+/// Fires the timer interrupt once, `ns` nanoseconds from now, replacing whatever was armed
+pub fn start_one_shot(ns: u64) {
+    TIMER.get().deadline.set(0);
+    program(TimerMode::OneShot, ns_to_ticks(ns));
+}
+
+/// Fires the timer interrupt every `period_ns` nanoseconds until `stop`
+pub fn start_periodic(period_ns: u64) {
+    TIMER.get().deadline.set(0);
+    program(TimerMode::Periodic, ns_to_ticks(period_ns));
+}
+
+pub fn stop() {
+    TIMER.get().deadline.set(0);
+    percpu::current().with_local_apic(|apic| {
+        apic.set_timer_initial_count(0);
+        apic.set_lvt(LvtEntry::Timer, Lvt::masked());
+        if has_tsc_deadline() {
+            unsafe { TscDeadline::write(0) };
+        }
+    });
+}
+
+/// Fires the timer interrupt on the executing core once the TSC reaches `deadline`, replacing
+/// whatever was armed. A deadline in the past fires right away.
+pub fn arm_deadline(deadline: u64) {
+    TIMER.get().deadline.set(deadline);
+    program_deadline(deadline);
+}
+
+/// The deadline armed on the executing core, `None` once it fired
+pub fn armed_deadline() -> Option<u64> {
+    Some(TIMER.get().deadline.get()).filter(|deadline| *deadline != 0)
+}
+
+/// Timer interrupts the executing core has taken, periodic ticks and expired deadlines
+pub fn expirations() -> u64 {
+    TIMER.get().expirations.get()
+}
+
+fn program(mode: TimerMode, ticks: u32) {
+    percpu::current().with_local_apic(|apic| {
+        apic.set_timer_initial_count(0);
+        apic.set_lvt(LvtEntry::Timer, Lvt::new(TIMER_VECTOR).with_timer_mode(mode));
+        apic.set_timer_divide(DIVIDE);
+        apic.set_timer_initial_count(ticks);
+    });
+}
+
+fn program_deadline(deadline: u64) {
+    if has_tsc_deadline() {
+        percpu::current().with_local_apic(|apic| {
+            apic.set_timer_initial_count(0);
+            apic.set_lvt(LvtEntry::Timer, Lvt::new(TIMER_VECTOR).with_timer_mode(TimerMode::TscDeadline));
+        });
+        // The LVT write has to land before the MSR write, or the deadline is ignored
+        unsafe {
+            core::arch::asm!("mfence", options(nostack, preserves_flags));
+            TscDeadline::write(deadline);
+        }
+        return;
+    }
+    // Long waits run out the 32 bit count early, the interrupt handler re-arms until it's reached
+    let remaining = deadline.saturating_sub(tsc::read());
+    program(TimerMode::OneShot, ns_to_ticks(tsc::cycles_to_ns(remaining)));
+}
+
+extern "x86-interrupt" fn timer_interrupt(_frame: InterruptStackFrame) {
+    let timer = TIMER.get();
+    let deadline = timer.deadline.get();
+    if deadline != 0 && tsc::read() < deadline {
+        program_deadline(deadline);
+    } else {
+        timer.deadline.set(0);
+        timer.expirations.set(timer.expirations.get() + 1);
+    }
+    percpu::current().with_local_apic(|apic| apic.eoi());
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![
+        frequency_is_plausible,
+        ns_to_ticks_rounds_and_clamps,
+        one_shot_fires_once,
+        periodic_keeps_firing,
+        deadline_fires_after_it_passed,
+    ];
+
+    /// Generous, QEMU's TCG timers can be late
+    const FIRE_TIMEOUT_US: u64 = 100_000;
+
+    fn frequency_is_plausible() {
+        // 1 MHz up to a 1 GHz crystal, divided by 16
+        assert!((1_000_000..100_000_000).contains(&frequency()), "APIC timer at {} Hz", frequency());
+    }
+
+    fn ns_to_ticks_rounds_and_clamps() {
+        assert_eq!(ns_to_ticks(0), 1);
+        assert_eq!(ns_to_ticks(u64::MAX), u32::MAX);
+        let ticks = ns_to_ticks(1_000_000) as u64;
+        assert!(ticks.abs_diff(frequency() / 1000) <= 1);
+    }
+
+    fn one_shot_fires_once() {
+        let before = expirations();
+        start_one_shot(1_000_000);
+        assert!(tsc::wait_until(FIRE_TIMEOUT_US, || expirations() > before), "one-shot timer never fired");
+        tsc::delay_us(5_000);
+        assert_eq!(expirations(), before + 1);
+    }
+
+    fn periodic_keeps_firing() {
+        let before = expirations();
+        start_periodic(1_000_000);
+        let fired = tsc::wait_until(FIRE_TIMEOUT_US, || expirations() >= before + 3);
+        stop();
+        assert!(fired, "periodic timer fired {} times", expirations() - before);
+        let stopped = expirations();
+        tsc::delay_us(5_000);
+        assert_eq!(expirations(), stopped);
+    }
+
+    fn deadline_fires_after_it_passed() {
+        let before = expirations();
+        let deadline = tsc::read() + tsc::us_to_cycles(2_000);
+        arm_deadline(deadline);
+        assert_eq!(armed_deadline(), Some(deadline));
+        assert!(tsc::wait_until(FIRE_TIMEOUT_US, || expirations() > before), "deadline never fired");
+        assert!(tsc::read() >= deadline);
+        assert_eq!(armed_deadline(), None);
+    }
+}
@@ -0,0 +1,183 @@
+use core::{
+    arch::x86_64::_rdtsc,
+    cell::UnsafeCell,
+    fmt::Write,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{percpu::core_index, serial::COM1, MAX_PROC_COUNT};
+
+// Binary trace records. `trace!` interns its format string in the `unclad_trace_fmt` section and
+// only stores the string's offset, the raw arguments and a TSC timestamp in a per-core ring.
+// One core drains the rings to serial as `~T <hex>` lines, which the host runner decodes
+// against the kernel ELF, so tracing never formats or takes a shared lock on the hot path.
+
+pub const MAX_ARGS: usize = 4;
+const RING_LEN: u32 = 256;
+/// Prefix of a drained record on the serial line, must match the host decoder
+pub const LINE_PREFIX: &str = "~T ";
+
+/// Held while draining, the rings only have room for one consumer
+static DRAINING: Mutex<()> = Mutex::new(());
+
+unsafe extern "C" {
+    // Provided by the linker for the section holding every interned format string
+    static __start_unclad_trace_fmt: u8;
+}
+
+#[derive(Clone, Copy)]
+struct TraceRecord {
+    tsc: u64,
+    fmt_id: u32,
+    arg_count: u8,
+    args: [u64; MAX_ARGS],
+}
+
+impl TraceRecord {
+    const fn empty() -> Self {
+        Self {
+            tsc: 0,
+            fmt_id: 0,
+            arg_count: 0,
+            args: [0; MAX_ARGS],
+        }
+    }
+}
+
+/// Single producer (the owning core), single consumer (the draining core)
+struct TraceRing {
+    head: AtomicU32,
+    tail: AtomicU32,
+    dropped: AtomicU32,
+    records: [UnsafeCell<TraceRecord>; RING_LEN as usize],
+}
+
+// SAFETY: A slot is only written by the owning core between `tail` and `head + 1`, and only read by
+// the consumer once `head` has been published past it
+unsafe impl Sync for TraceRing {}
+
+impl TraceRing {
+    const fn new() -> Self {
+        Self {
+            head: AtomicU32::new(0),
+            tail: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+            records: [const { UnsafeCell::new(TraceRecord::empty()) }; RING_LEN as usize],
+        }
+    }
+
+    fn push(&self, record: TraceRecord) {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) >= RING_LEN {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        unsafe { *self.records[(head % RING_LEN) as usize].get() = record };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<TraceRecord> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail == head {
+            return None;
+        }
+        let record = unsafe { *self.records[(tail % RING_LEN) as usize].get() };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(record)
+    }
+}
+
+static RINGS: [TraceRing; MAX_PROC_COUNT] = [const { TraceRing::new() }; MAX_PROC_COUNT];
+
+/// Copies a format string into a NUL terminated array, used by `trace!` to intern it
+pub const fn nul_terminate<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+}
+
+#[doc(hidden)]
+pub fn record(fmt: &'static u8, args: &[u64]) {
+    let Some(ring) = RINGS.get(core_index()) else {
+        return;
+    };
+    let base = unsafe { &__start_unclad_trace_fmt } as *const u8 as usize;
+    let mut record = TraceRecord {
+        tsc: unsafe { _rdtsc() },
+        fmt_id: (fmt as *const u8 as usize - base) as u32,
+        arg_count: args.len().min(MAX_ARGS) as u8,
+        args: [0; MAX_ARGS],
+    };
+    record.args[..record.arg_count as usize].copy_from_slice(&args[..record.arg_count as usize]);
+    // The owning core is the only producer, keep its own IRQ handlers from interleaving with it
+    interrupts::without_interrupts(|| ring.push(record));
+}
+
+/// Records a compact trace event on the current core's ring. Arguments are cast to `u64` and
+/// formatted on the host, which understands `{}`, `{:x}` and `{:#x}`.
+#[macro_export]
+macro_rules! trace {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {{
+        const _: () = assert!(
+            <[&str]>::len(&[$(stringify!($arg)),*]) <= $crate::trace::MAX_ARGS,
+            "too many trace arguments"
+        );
+        #[unsafe(link_section = "unclad_trace_fmt")]
+        static FMT: [u8; $fmt.len() + 1] = $crate::trace::nul_terminate($fmt);
+        $crate::trace::record(&FMT[0], &[$($arg as u64),*]);
+    }};
+}
+
+fn write_hex(out: &mut impl Write, bytes: &[u8]) {
+    for byte in bytes {
+        let _ = write!(out, "{:02x}", byte);
+    }
+}
+
+/// Writes every pending record of every core to COM1, returns how many were drained
+pub fn drain() -> usize {
+    let _draining = DRAINING.lock();
+    drain_rings()
+}
+
+/// `drain` for the crash and exit paths, `None` if another drain is in progress. That may be the
+/// one the crash interrupted, which would never let go of the rings.
+pub fn try_drain() -> Option<usize> {
+    let _draining = DRAINING.try_lock()?;
+    Some(drain_rings())
+}
+
+fn drain_rings() -> usize {
+    let mut drained = 0;
+    for (core, ring) in RINGS.iter().enumerate() {
+        let dropped = ring.dropped.swap(0, Ordering::Relaxed);
+        if dropped != 0 {
+            log::warn!("Trace ring of core {} dropped {} records", core, dropped);
+        }
+        while let Some(record) = ring.pop() {
+            interrupts::without_interrupts(|| {
+                let mut port = COM1.lock();
+                let _ = port.write_str(LINE_PREFIX);
+                write_hex(&mut *port, &[core as u8, record.arg_count]);
+                write_hex(&mut *port, &record.fmt_id.to_le_bytes());
+                write_hex(&mut *port, &record.tsc.to_le_bytes());
+                for arg in &record.args[..record.arg_count as usize] {
+                    write_hex(&mut *port, &arg.to_le_bytes());
+                }
+                let _ = port.write_str("\r\n");
+            });
+            drained += 1;
+        }
+    }
+    drained
+}
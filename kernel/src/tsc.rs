@@ -0,0 +1,243 @@
+use core::{
+    arch::x86_64::{__cpuid, _mm_lfence, _rdtsc},
+    hint,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+
+use conquer_once::spin::OnceCell;
+
+use crate::calibration::{self, Reference};
+
+// Time stamp counter, the kernel's clocksource. Its frequency comes from CPUID where the CPU
+// enumerates it, otherwise it's calibrated once on the BSP against the best reference. Every AP's
+// TSC is checked against the BSP's while it comes up. All delays and timeouts that have to mean
+// the same on QEMU and real hardware go through here.
+
+const CALIBRATION_US: u64 = 10_000;
+const CALIBRATION_ROUNDS: usize = 3;
+const SYNC_ROUNDS: u32 = 64;
+const SYNC_TIMEOUT_US: u64 = 100_000;
+
+static TSC_HZ: OnceCell<(u64, FrequencySource)> = OnceCell::uninit();
+/// Nanoseconds per cycle as a 32.32 fixed point number
+static NS_PER_CYCLE: OnceCell<u64> = OnceCell::uninit();
+/// CPUID.80000007H:EDX.InvariantTSC, cached since CPUID traps under a hypervisor
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+/// Cleared once a core's TSC is seen out of step with the BSP's
+static SYNCHRONIZED: AtomicBool = AtomicBool::new(true);
+
+// Shared by the two sides of `check_sync`
+static SYNC_TURN: AtomicU32 = AtomicU32::new(0);
+static SYNC_STAMP: AtomicU64 = AtomicU64::new(0);
+static SYNC_WARP: AtomicU64 = AtomicU64::new(0);
+
+/// Where `frequency` came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrequencySource {
+    /// CPUID leaf 0x15, the crystal clock times the TSC to crystal ratio
+    Crystal,
+    /// CPUID leaf 0x16, the nominal base frequency the TSC runs at
+    BaseFrequency,
+    Calibrated(Reference),
+}
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// `read`, but not before the loads and stores ahead of it completed
+pub fn read_ordered() -> u64 {
+    unsafe {
+        _mm_lfence();
+        _rdtsc()
+    }
+}
+
+/// Takes the TSC frequency from CPUID or measures it against the best reference, the HPET once
+/// it's up
+pub(crate) fn calibrate() {
+    let (hz, source) = cpuid_frequency().unwrap_or_else(|| {
+        let reference = Reference::best();
+        let hz = calibration::frequency(reference, CALIBRATION_US, CALIBRATION_ROUNDS, read);
+        (hz, FrequencySource::Calibrated(reference))
+    });
+    TSC_HZ.init_once(|| (hz, source));
+    NS_PER_CYCLE.init_once(|| ((1_000_000_000u128 << 32) / hz as u128) as u64);
+    let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    let invariant = max_leaf >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0;
+    INVARIANT.store(invariant, Ordering::Relaxed);
+    log::info!("TSC at {} kHz ({:?}), invariant: {}", hz / 1000, source, is_invariant());
+}
+
+fn cpuid_frequency() -> Option<(u64, FrequencySource)> {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 0x15 {
+        // EAX denominator, EBX numerator, ECX crystal Hz, any of them 0 if not enumerated
+        let leaf = unsafe { __cpuid(0x15) };
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some((leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64, FrequencySource::Crystal));
+        }
+    }
+    if max_leaf >= 0x16 {
+        let base_mhz = unsafe { __cpuid(0x16) }.eax & 0xFFFF;
+        if base_mhz != 0 {
+            return Some((base_mhz as u64 * 1_000_000, FrequencySource::BaseFrequency));
+        }
+    }
+    None
+}
+
+/// TSC ticks per second
+pub fn frequency() -> u64 {
+    TSC_HZ.get().expect("TSC not calibrated").0
+}
+
+pub fn frequency_source() -> FrequencySource {
+    TSC_HZ.get().expect("TSC not calibrated").1
+}
+
+/// The TSC runs at a constant rate through P-, C- and T-states
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Whether every started AP's TSC matched the BSP's
+pub fn is_synchronized() -> bool {
+    SYNCHRONIZED.load(Ordering::Relaxed)
+}
+
+/// Whether readings from different cores and far apart in time can be compared directly
+pub fn is_reliable() -> bool {
+    is_invariant() && is_synchronized()
+}
+
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    let ns_per_cycle = *NS_PER_CYCLE.get().expect("TSC not calibrated");
+    ((cycles as u128 * ns_per_cycle as u128) >> 32) as u64
+}
+
+pub fn us_to_cycles(us: u64) -> u64 {
+    frequency() * us / 1_000_000
+}
+
+pub fn delay_us(us: u64) {
+    let end = read() + us_to_cycles(us);
+    while read() < end {
+        hint::spin_loop();
+    }
+}
+
+/// Spins until `condition` holds or `timeout_us` microseconds passed, returns whether it held
+pub fn wait_until(timeout_us: u64, mut condition: impl FnMut() -> bool) -> bool {
+    let end = read() + us_to_cycles(timeout_us);
+    loop {
+        if condition() {
+            return true;
+        }
+        if read() >= end {
+            return false;
+        }
+        hint::spin_loop();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SyncSide {
+    Bsp,
+    Ap,
+}
+
+/// Run by the BSP before starting an AP that joins it in `check_sync`
+pub(crate) fn reset_sync_check() {
+    SYNC_TURN.store(0, Ordering::Relaxed);
+    SYNC_STAMP.store(0, Ordering::Relaxed);
+    SYNC_WARP.store(0, Ordering::Relaxed);
+}
+
+/// Both cores take turns reading their TSC right after seeing the other's reading, which it can
+/// never be behind if the two are in step. Returns the largest gap in cycles, or `None` if the
+/// other side never took its turn.
+pub(crate) fn check_sync(side: SyncSide) -> Option<u64> {
+    let first = match side {
+        SyncSide::Bsp => 0,
+        SyncSide::Ap => 1,
+    };
+    for turn in (first..2 * SYNC_ROUNDS).step_by(2) {
+        if !wait_until(SYNC_TIMEOUT_US, || SYNC_TURN.load(Ordering::Acquire) == turn) {
+            return None;
+        }
+        let previous = SYNC_STAMP.load(Ordering::Relaxed);
+        let now = read_ordered();
+        if now < previous {
+            SYNC_WARP.fetch_max(previous - now, Ordering::Relaxed);
+        }
+        SYNC_STAMP.store(now, Ordering::Relaxed);
+        SYNC_TURN.store(turn + 1, Ordering::Release);
+    }
+    // The AP has the last turn
+    if side == SyncSide::Bsp && !wait_until(SYNC_TIMEOUT_US, || SYNC_TURN.load(Ordering::Acquire) == 2 * SYNC_ROUNDS) {
+        return None;
+    }
+    let warp = SYNC_WARP.load(Ordering::Relaxed);
+    if warp != 0 {
+        SYNCHRONIZED.store(false, Ordering::Relaxed);
+    }
+    Some(warp)
+}
+
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{pit, testing::{self, test_cases}};
+
+    test_cases![
+        frequency_is_plausible,
+        cpuid_frequency_matches_measurement,
+        cycles_to_ns_scales,
+        delay_matches_pit,
+        wait_until_times_out,
+        sync_check_times_out_alone,
+    ];
+
+    fn frequency_is_plausible() {
+        // Anything between an emulated 100 MHz and a 10 GHz part
+        assert!((100_000_000..10_000_000_000).contains(&frequency()), "TSC at {} Hz", frequency());
+    }
+
+    fn cpuid_frequency_matches_measurement() {
+        let Some((hz, _)) = cpuid_frequency() else {
+            return testing::skip("CPUID doesn't report the TSC frequency");
+        };
+        let measured = calibration::frequency(Reference::best(), 2_000, 2, read);
+        assert!(testing::timing_matches(hz, measured), "CPUID says {} Hz, measured {} Hz", hz, measured);
+    }
+
+    fn cycles_to_ns_scales() {
+        assert_eq!(cycles_to_ns(0), 0);
+        let second = cycles_to_ns(frequency());
+        assert!(second.abs_diff(1_000_000_000) <= 1, "{} ns", second);
+    }
+
+    fn delay_matches_pit() {
+        let ticks = pit::ticks_for_us(5_000);
+        let (mut start, mut end) = (0, 0);
+        pit::measure(ticks, || start = read(), || end = read());
+        let expected = us_to_cycles(ticks as u64 * 1_000_000 / pit::PIT_FREQUENCY);
+        let measured = end - start;
+        assert!(testing::timing_matches(measured, expected), "{} cycles, expected {}", measured, expected);
+    }
+
+    fn wait_until_times_out() {
+        assert!(wait_until(100, || true));
+        let start = read();
+        assert!(!wait_until(1_000, || false));
+        assert!(read() - start >= us_to_cycles(1_000));
+    }
+
+    fn sync_check_times_out_alone() {
+        reset_sync_check();
+        assert_eq!(check_sync(SyncSide::Bsp), None);
+        assert!(is_synchronized());
+        reset_sync_check();
+    }
+}
@@ -101,4 +101,46 @@ macro_rules! assert_aligned {
     };
 }
 
-pub(crate) use assert_aligned;
\ No newline at end of file
+pub(crate) use assert_aligned;
+#[cfg(feature = "kernel-tests")]
+pub(crate) mod tests {
+    use x86_64::structures::paging::{Size2MiB, Size4KiB};
+
+    use super::*;
+    use crate::testing::test_cases;
+
+    test_cases![
+        frame_numeric_rejects_unaligned,
+        frame_numeric_round_trips,
+        frame_numeric_respects_page_size,
+        assert_aligned_accepts_aligned,
+    ];
+
+    fn frame_numeric_rejects_unaligned() {
+        assert!(FrameNumeric::<Size4KiB>::try_from(0x1001u64).is_err());
+        assert!(FrameNumeric::<Size4KiB>::try_from(0x1001usize).is_err());
+        assert_eq!(FrameNumeric::<Size4KiB>::try_from(0x3000u64).unwrap().num, 3);
+    }
+
+    fn frame_numeric_round_trips() {
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x7000));
+        let numeric = FrameNumeric::from(frame);
+        assert_eq!(numeric.num, 7);
+        assert_eq!(PhysFrame::from(numeric), frame);
+        assert_eq!(PhysAddr::from(numeric).as_u64(), 0x7000);
+        assert_eq!(usize::from(numeric), 7);
+        assert_eq!(ToFrameNumeric::<Size4KiB>::to_frame_numeric(&frame), 7);
+    }
+
+    fn frame_numeric_respects_page_size() {
+        let numeric = FrameNumeric::<Size2MiB>::from_num(3);
+        assert_eq!(PhysAddr::from(numeric).as_u64(), 3 * 0x20_0000);
+        assert!(FrameNumeric::<Size2MiB>::try_from(0x1000u64).is_err());
+        assert_eq!(ToFrameNumeric::<Size2MiB>::to_frame_numeric(&0x40_0000u64), 2);
+    }
+
+    fn assert_aligned_accepts_aligned() {
+        assert_aligned!(0x2000u64, 0x1000u64);
+        assert_aligned!(0u64, 0x20_0000u64);
+    }
+}
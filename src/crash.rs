@@ -0,0 +1,185 @@
+use std::{fmt::Write, path::Path};
+
+use object::{Object, ObjectSymbol, SymbolKind};
+
+/// Delimiters of the kernel's crash report, must match `kernel::crash`
+pub const BEGIN_MARKER: &str = "~CRASH BEGIN";
+pub const END_MARKER: &str = "~CRASH END";
+
+/// The `key: value` lines the kernel writes between the crash markers
+#[derive(Debug, Default)]
+pub struct CrashReport {
+    pub message: String,
+    pub core: Option<u64>,
+    pub image_offset: u64,
+    pub rip: Option<u64>,
+    pub stack_ref: Option<String>,
+    pub frames: Vec<u64>,
+    /// Registers and anything else not handled above, in report order
+    pub fields: Vec<(String, String)>,
+}
+
+fn parse_u64(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+impl CrashReport {
+    pub fn parse<'a>(lines: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut report = CrashReport::default();
+        for line in lines {
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+            match key {
+                "message" => report.message = value.replace("\\n", "\n"),
+                "core" => report.core = parse_u64(value),
+                "image_offset" => report.image_offset = parse_u64(value).unwrap_or(0),
+                "rip" => report.rip = parse_u64(value),
+                "stack_ref" => report.stack_ref = Some(value.to_owned()),
+                "frame" => report.frames.extend(parse_u64(value)),
+                _ => report.fields.push((key.to_owned(), value.to_owned())),
+            }
+        }
+        report
+    }
+}
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+/// Resolves kernel addresses to `function+offset` using the ELF symbol table
+pub struct Symbolizer {
+    symbols: Vec<Symbol>,
+}
+
+impl Symbolizer {
+    pub fn from_elf(kernel: &Path) -> Result<Self, String> {
+        let data = std::fs::read(kernel).map_err(|e| format!("reading {}: {e}", kernel.display()))?;
+        let elf = object::File::parse(&*data).map_err(|e| format!("parsing {}: {e}", kernel.display()))?;
+        let mut symbols: Vec<Symbol> = elf
+            .symbols()
+            .filter(|s| s.kind() == SymbolKind::Text && s.address() != 0)
+            .filter_map(|s| {
+                Some(Symbol {
+                    address: s.address(),
+                    size: s.size(),
+                    name: format!("{:#}", rustc_demangle::demangle(s.name().ok()?)),
+                })
+            })
+            .collect();
+        symbols.sort_by_key(|s| s.address);
+        Ok(Self { symbols })
+    }
+
+    /// Looks up an ELF virtual address, runtime addresses need the image offset subtracted first
+    pub fn symbolize(&self, address: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|s| s.address <= address).checked_sub(1)?;
+        let symbol = &self.symbols[index];
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((&symbol.name, offset))
+    }
+
+    fn describe(&self, report: &CrashReport, runtime_address: u64) -> String {
+        match self.symbolize(runtime_address.wrapping_sub(report.image_offset)) {
+            Some((name, offset)) => format!("{runtime_address:#018x} {name}+{offset:#x}"),
+            None => format!("{runtime_address:#018x} <unknown>"),
+        }
+    }
+
+    /// Human readable summary of a crash report with a symbolized backtrace
+    pub fn format_report(&self, report: &CrashReport) -> String {
+        let mut out = String::new();
+        let core = report.core.map_or("?".to_owned(), |c| c.to_string());
+        let _ = writeln!(out, "Kernel crash on core {core}: {}", report.message);
+        if let Some(stack_ref) = &report.stack_ref {
+            let _ = writeln!(out, "  stack: {stack_ref}");
+        }
+        if let Some(rip) = report.rip {
+            let _ = writeln!(out, "  at   {}", self.describe(report, rip));
+        }
+        for (i, frame) in report.frames.iter().enumerate() {
+            // Return addresses point after the call, step back into it for the lookup
+            let _ = writeln!(out, "  #{i:<3} {}", self.describe(report, frame.saturating_sub(1)));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &[&str] = &[
+        "message: assertion failed\\nleft: 1",
+        "core: 2",
+        "image_offset: 0x8000000000",
+        "rip: 0x8000001010",
+        "registers: exception handler, except rsp and rbp",
+        "rax: 0x5",
+        "not a field",
+        "stack_ref: 3 (base 0xffff800000100000)",
+        "frame: 0x8000002008",
+        "frame: 0x8000000001",
+    ];
+
+    fn symbolizer() -> Symbolizer {
+        let symbol = |address, size, name: &str| Symbol { address, size, name: name.to_owned() };
+        Symbolizer {
+            symbols: vec![symbol(0x1000, 0x100, "kernel::crash"), symbol(0x2000, 0, "kernel::unsized")],
+        }
+    }
+
+    #[test]
+    fn parses_reports() {
+        let report = CrashReport::parse(REPORT.iter().copied());
+        assert_eq!(report.message, "assertion failed\nleft: 1");
+        assert_eq!(report.core, Some(2));
+        assert_eq!(report.image_offset, 0x80_0000_0000);
+        assert_eq!(report.rip, Some(0x80_0000_1010));
+        assert_eq!(report.stack_ref.as_deref(), Some("3 (base 0xffff800000100000)"));
+        assert_eq!(report.frames, [0x80_0000_2008, 0x80_0000_0001]);
+        let fields: Vec<(&str, &str)> = report.fields.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(fields, [("registers", "exception handler, except rsp and rbp"), ("rax", "0x5")]);
+    }
+
+    #[test]
+    fn unparsable_values_are_dropped() {
+        let report = CrashReport::parse(["core: two", "rip: 0xzz", "frame: nowhere", "image_offset: ?"]);
+        assert_eq!(report.core, None);
+        assert_eq!(report.rip, None);
+        assert!(report.frames.is_empty());
+        assert_eq!(report.image_offset, 0);
+    }
+
+    #[test]
+    fn symbolizes_addresses() {
+        let symbolizer = symbolizer();
+        assert_eq!(symbolizer.symbolize(0x1000), Some(("kernel::crash", 0)));
+        assert_eq!(symbolizer.symbolize(0x10ff), Some(("kernel::crash", 0xff)));
+        assert_eq!(symbolizer.symbolize(0x1100), None);
+        assert_eq!(symbolizer.symbolize(0xfff), None);
+        // Without a size a symbol runs up to the next one
+        assert_eq!(symbolizer.symbolize(0x2345), Some(("kernel::unsized", 0x345)));
+    }
+
+    #[test]
+    fn formats_reports_with_the_image_offset_removed() {
+        let report = CrashReport::parse(REPORT.iter().copied());
+        let formatted = symbolizer().format_report(&report);
+        let expected = "Kernel crash on core 2: assertion failed\nleft: 1\n\
+                        \x20 stack: 3 (base 0xffff800000100000)\n\
+                        \x20 at   0x0000008000001010 kernel::crash+0x10\n\
+                        \x20 #0   0x0000008000002007 kernel::unsized+0x7\n\
+                        \x20 #1   0x0000008000000000 <unknown>\n";
+        assert_eq!(formatted, expected);
+    }
+}
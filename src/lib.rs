@@ -0,0 +1,6 @@
+// src/lib.rs
+// Host-side helpers shared by the runner and the integration tests
+
+pub mod crash;
+pub mod qemu;
+pub mod trace;
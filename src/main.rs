@@ -1,32 +1,137 @@
 // src/main.rs
 
+use std::{
+    io::{BufRead, BufReader},
+    path::Path,
+    process::Stdio,
+};
+
+use clap::{Parser, ValueEnum};
+use unclad::{
+    crash::{self, CrashReport, Symbolizer},
+    qemu::{QemuExitCode, debug_exit_device_args},
+    trace::TraceDecoder,
+};
+
+/// Boots the unclad kernel image in QEMU
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Firmware to boot the kernel image with
+    #[arg(short, long, value_enum, default_value_t = Firmware::Bios)]
+    firmware: Firmware,
+
+    /// Number of CPUs to emulate
+    #[arg(long, default_value_t = 2)]
+    smp: u8,
+
+    /// Guest memory size, in QEMU's `-m` syntax (e.g. 512M, 2G)
+    #[arg(short, long, default_value = "128M")]
+    memory: String,
+
+    /// Emulated machine type
+    #[arg(long, value_enum, default_value_t = Machine::Pc)]
+    machine: Machine,
+
+    /// Start a gdb server on tcp::1234 and wait for a debugger before running
+    #[arg(short, long)]
+    gdb: bool,
+
+    /// QEMU `-d` log items, or "none" to disable QEMU logging
+    #[arg(short = 'd', long, default_value = "guest_errors,cpu_reset,int")]
+    log: String,
+
+    /// Extra arguments passed to QEMU verbatim, after `--`
+    #[arg(last = true)]
+    qemu_args: Vec<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Firmware {
+    Bios,
+    Uefi,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Machine {
+    Pc,
+    Q35,
+}
+
+impl Machine {
+    fn as_qemu_arg(&self) -> &'static str {
+        match self {
+            Machine::Pc => "pc",
+            Machine::Q35 => "q35",
+        }
+    }
+}
+
 fn main() {
     // read env variables that were set in build script
     let uefi_path = env!("UEFI_PATH");
     let bios_path = env!("BIOS_PATH");
-    
-    // choose whether to start the UEFI or BIOS image
-    let uefi = false;
-    let debug = false;
+    let kernel_path = env!("KERNEL_PATH");
+
+    let args = Args::parse();
 
     let mut cmd = std::process::Command::new("qemu-system-x86_64");
-    if debug {
+    if args.gdb {
         cmd.arg("-s");
         cmd.arg("-S");
     }
     cmd.arg("-no-reboot");
     cmd.arg("-no-shutdown");
     cmd.arg("-serial").arg("stdio");
-    cmd.arg("-smp").arg("2");
-    cmd.arg("-d").arg("guest_errors,cpu_reset,int");
-    if uefi {
-        cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
-        cmd.arg("-drive").arg(format!("format=raw,file={uefi_path}"));
-        
-    } else {
-
-        cmd.arg("-drive").arg(format!("format=raw,file={bios_path}"));
+    cmd.args(debug_exit_device_args());
+    cmd.arg("-machine").arg(args.machine.as_qemu_arg());
+    cmd.arg("-smp").arg(args.smp.to_string());
+    cmd.arg("-m").arg(&args.memory);
+    if args.log != "none" {
+        cmd.arg("-d").arg(&args.log);
     }
+    match args.firmware {
+        Firmware::Uefi => {
+            cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+            cmd.arg("-drive").arg(format!("format=raw,file={uefi_path}"));
+        }
+        Firmware::Bios => {
+            cmd.arg("-drive").arg(format!("format=raw,file={bios_path}"));
+        }
+    }
+    cmd.args(&args.qemu_args);
+    cmd.stdout(Stdio::piped());
+
+    let decoder = TraceDecoder::from_elf(Path::new(kernel_path))
+        .map_err(|e| eprintln!("Trace decoding disabled: {e}"))
+        .ok();
+    let symbolizer = Symbolizer::from_elf(Path::new(kernel_path))
+        .map_err(|e| eprintln!("Crash symbolization disabled: {e}"))
+        .ok();
     let mut child = cmd.spawn().unwrap();
-    child.wait().unwrap();
-}
\ No newline at end of file
+    let serial = BufReader::new(child.stdout.take().unwrap());
+    let mut crash_lines: Option<Vec<String>> = None;
+    for line in serial.lines() {
+        let Ok(line) = line else { break };
+        let line = line.trim_end_matches('\r');
+        match decoder.as_ref().and_then(|d| d.decode_line(line)) {
+            Some(decoded) => println!("{decoded}"),
+            None => println!("{line}"),
+        }
+        if line == crash::BEGIN_MARKER {
+            crash_lines = Some(Vec::new());
+        } else if line == crash::END_MARKER {
+            let (Some(lines), Some(symbolizer)) = (crash_lines.take(), symbolizer.as_ref()) else {
+                continue;
+            };
+            let report = CrashReport::parse(lines.iter().map(String::as_str));
+            print!("{}", symbolizer.format_report(&report));
+        } else if let Some(lines) = crash_lines.as_mut() {
+            lines.push(line.to_owned());
+        }
+    }
+    let status = child.wait().unwrap();
+    if QemuExitCode::from_status(status) == Some(QemuExitCode::Failed) {
+        std::process::exit(1);
+    }
+}
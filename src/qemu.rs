@@ -0,0 +1,99 @@
+use std::{
+    io::Read,
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    thread,
+    time::Duration,
+};
+
+use wait_timeout::ChildExt;
+
+/// I/O port of the `isa-debug-exit` device, must match `kernel::qemu::ISA_DEBUG_EXIT_PORT`
+pub const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Codes the kernel writes to the `isa-debug-exit` device, mirrors `kernel::qemu::QemuExitCode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+impl QemuExitCode {
+    /// QEMU exits with `(code << 1) | 1` when the guest writes `code` to the debug exit port
+    pub fn from_status(status: ExitStatus) -> Option<Self> {
+        match status.code()? {
+            c if c == (Self::Success as i32) << 1 | 1 => Some(Self::Success),
+            c if c == (Self::Failed as i32) << 1 | 1 => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Arguments adding the `isa-debug-exit` device to a QEMU command line
+pub fn debug_exit_device_args() -> [String; 2] {
+    [
+        "-device".into(),
+        format!("isa-debug-exit,iobase={ISA_DEBUG_EXIT_PORT:#x},iosize=0x04"),
+    ]
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestOutcome {
+    /// The kernel wrote `QemuExitCode::Success` to the debug exit port
+    Passed,
+    /// The kernel wrote `QemuExitCode::Failed` to the debug exit port
+    Failed,
+    /// QEMU exited without going through the debug exit port, e.g. after a triple fault
+    Crashed(Option<i32>),
+    /// The kernel did not exit before the timeout and QEMU was killed
+    TimedOut,
+}
+
+#[derive(Debug)]
+pub struct TestReport {
+    pub outcome: TestOutcome,
+    /// Everything the kernel wrote to COM1
+    pub serial: String,
+}
+
+/// Boots a BIOS disk image headless and waits for it to exit through the debug exit device
+pub fn run_test_image(image: &Path, smp: u8, timeout: Duration) -> TestReport {
+    let mut cmd = Command::new("qemu-system-x86_64");
+    cmd.arg("-drive").arg(format!("format=raw,file={}", image.display()));
+    cmd.args(debug_exit_device_args());
+    cmd.arg("-no-reboot");
+    cmd.arg("-display").arg("none");
+    cmd.arg("-serial").arg("stdio");
+    cmd.arg("-smp").arg(smp.to_string());
+    cmd.stdin(Stdio::null());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::inherit());
+
+    let mut child = cmd.spawn().expect("failed to start qemu-system-x86_64");
+    let mut stdout = child.stdout.take().unwrap();
+    // Read serial on another thread so a chatty kernel can't block on a full pipe
+    let serial = thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stdout.read_to_end(&mut buf);
+        String::from_utf8_lossy(&buf).into_owned()
+    });
+
+    let outcome = match child.wait_timeout(timeout).unwrap() {
+        Some(status) => match QemuExitCode::from_status(status) {
+            Some(QemuExitCode::Success) => TestOutcome::Passed,
+            Some(QemuExitCode::Failed) => TestOutcome::Failed,
+            None => TestOutcome::Crashed(status.code()),
+        },
+        None => {
+            let _ = child.kill();
+            let _ = child.wait();
+            TestOutcome::TimedOut
+        }
+    };
+
+    TestReport {
+        outcome,
+        serial: serial.join().unwrap(),
+    }
+}
@@ -0,0 +1,158 @@
+use std::{fmt::Write, path::Path};
+
+use object::{Object, ObjectSection};
+
+/// Prefix of a trace record line, must match `kernel::trace::LINE_PREFIX`
+pub const LINE_PREFIX: &str = "~T ";
+/// Section the kernel interns `trace!` format strings into
+pub const FORMAT_SECTION: &str = "unclad_trace_fmt";
+
+// Fixed part of a record: core (u8), argument count (u8), format offset (u32), TSC (u64)
+const HEADER_LEN: usize = 1 + 1 + 4 + 8;
+
+/// Turns the kernel's binary trace records back into text using the format strings in its ELF
+pub struct TraceDecoder {
+    strings: Vec<u8>,
+}
+
+impl TraceDecoder {
+    pub fn from_elf(kernel: &Path) -> Result<Self, String> {
+        let data = std::fs::read(kernel).map_err(|e| format!("reading {}: {e}", kernel.display()))?;
+        let elf = object::File::parse(&*data).map_err(|e| format!("parsing {}: {e}", kernel.display()))?;
+        // A kernel without any `trace!` call has no format section, which is fine
+        let strings = match elf.section_by_name(FORMAT_SECTION) {
+            Some(section) => section.data().map_err(|e| e.to_string())?.to_vec(),
+            None => Vec::new(),
+        };
+        Ok(Self { strings })
+    }
+
+    fn format_string(&self, offset: usize) -> Option<&str> {
+        let bytes = self.strings.get(offset..)?;
+        let end = bytes.iter().position(|b| *b == 0)?;
+        std::str::from_utf8(&bytes[..end]).ok()
+    }
+
+    /// Decodes a `~T` serial line, returns `None` for anything else so it can be printed as is
+    pub fn decode_line(&self, line: &str) -> Option<String> {
+        let hex = line.trim_end().strip_prefix(LINE_PREFIX)?;
+        let bytes = decode_hex(hex)?;
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+        let core = bytes[0];
+        let arg_count = bytes[1] as usize;
+        let offset = u32::from_le_bytes(bytes[2..6].try_into().unwrap()) as usize;
+        let tsc = u64::from_le_bytes(bytes[6..14].try_into().unwrap());
+        let args: Vec<u64> = bytes[HEADER_LEN..]
+            .chunks_exact(8)
+            .take(arg_count)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        let message = match self.format_string(offset) {
+            Some(fmt) => apply_format(fmt, &args),
+            None => format!("<unknown trace format {offset:#x}> {args:x?}"),
+        };
+        Some(format!("[core {core} @ tsc {tsc}] {message}"))
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Supports the subset of format specs the kernel side documents: `{}`, `{:x}`, `{:#x}`
+fn apply_format(fmt: &str, args: &[u64]) -> String {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut rest = fmt;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start].replace("}}", "}"));
+        rest = &rest[start..];
+        if rest.starts_with("{{") {
+            out.push('{');
+            rest = &rest[2..];
+            continue;
+        }
+        let Some(end) = rest.find('}') else { break };
+        let spec = &rest[1..end];
+        rest = &rest[end + 1..];
+        let Some(arg) = args.next() else {
+            out.push_str("<missing>");
+            continue;
+        };
+        let _ = match spec {
+            ":x" => write!(out, "{arg:x}"),
+            ":#x" => write!(out, "{arg:#x}"),
+            _ => write!(out, "{arg}"),
+        };
+    }
+    out.push_str(&rest.replace("}}", "}"));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_line(core: u8, offset: u32, tsc: u64, args: &[u64]) -> String {
+        let mut bytes = vec![core, args.len() as u8];
+        bytes.extend_from_slice(&offset.to_le_bytes());
+        bytes.extend_from_slice(&tsc.to_le_bytes());
+        for arg in args {
+            bytes.extend_from_slice(&arg.to_le_bytes());
+        }
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        format!("{LINE_PREFIX}{hex}\r\n")
+    }
+
+    fn decoder() -> TraceDecoder {
+        TraceDecoder { strings: b"first\0AP {} at {:#x}\0".to_vec() }
+    }
+
+    #[test]
+    fn decodes_records() {
+        let line = record_line(2, 6, 1234, &[3, 0xbeef]);
+        assert_eq!(decoder().decode_line(&line).unwrap(), "[core 2 @ tsc 1234] AP 3 at 0xbeef");
+        let line = record_line(0, 0, 5, &[]);
+        assert_eq!(decoder().decode_line(&line).unwrap(), "[core 0 @ tsc 5] first");
+    }
+
+    #[test]
+    fn unknown_format_keeps_the_arguments() {
+        let line = record_line(1, 0x400, 9, &[0x10]);
+        assert_eq!(decoder().decode_line(&line).unwrap(), "[core 1 @ tsc 9] <unknown trace format 0x400> [10]");
+    }
+
+    #[test]
+    fn other_lines_are_left_alone() {
+        let decoder = decoder();
+        assert_eq!(decoder.decode_line("INFO : booting"), None);
+        assert_eq!(decoder.decode_line("~T 0102"), None);
+        assert_eq!(decoder.decode_line("~T zz"), None);
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("0g"), None);
+    }
+
+    #[test]
+    fn applies_formats() {
+        assert_eq!(apply_format("{} {:x} {:#x}", &[10, 255, 255]), "10 ff 0xff");
+        assert_eq!(apply_format("{{{}}} }}", &[1]), "{1} }");
+        assert_eq!(apply_format("{} and {}", &[1]), "1 and <missing>");
+        assert_eq!(apply_format("no arguments", &[1, 2]), "no arguments");
+        // An unterminated spec is printed as is
+        assert_eq!(apply_format("value {", &[1]), "value {");
+    }
+}
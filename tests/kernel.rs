@@ -0,0 +1,27 @@
+// Boots each `test_*` kernel binary built by build.rs and checks how it exits
+
+use std::{path::Path, time::Duration};
+
+use unclad::qemu::{TestOutcome, run_test_image};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const SMP: u8 = 2;
+
+macro_rules! kernel_test {
+    ($name:ident, $image:expr, $should_panic:expr) => {
+        #[test]
+        fn $name() {
+            let report = run_test_image(Path::new($image), SMP, TIMEOUT);
+            // Only shown by the test harness when the test fails
+            println!("{}", report.serial);
+            let expected = if $should_panic {
+                TestOutcome::Failed
+            } else {
+                TestOutcome::Passed
+            };
+            assert_eq!(report.outcome, expected);
+        }
+    };
+}
+
+include!(concat!(env!("OUT_DIR"), "/kernel_tests.rs"));